@@ -0,0 +1,333 @@
+use std::fs;
+use std::path::PathBuf;
+
+use serenity::{
+  http::Http,
+  model::guild::Member
+};
+
+use crate::data::config::{self, ConfigFile};
+use crate::data::format::Format;
+use crate::data::persist::{self, PersistFile};
+use crate::data::store::{self, Store};
+use crate::error::Error;
+use crate::util::LogLevel;
+
+pub const USAGE: &str = "\
+Usage: a3f_sentinel [OPTIONS] [COMMAND]
+
+Commands:
+  run                       Run the bot (the default)
+  check-config              Load and validate the config without connecting
+  setup                     Interactively create a config file
+  persist dump              Print the persist file
+  persist import <FILE>     Replace the persist file with the contents of another file
+  persist migrate <FORMAT>  Convert the persist file to another format (upgrading its layout)
+  persist convert <PATH>    Copy the persist state into another file or an SQLite database (.db)
+  export roster             Print a CSV of guild members with their rank and position
+  version                   Print the version
+
+Options:
+  --config <PATH>           Config file to use (default: config.json, .ron or .toml)
+  --persist <PATH>          Persist file to use (default: persist.json, .ron, .toml or .db)
+  --format <FORMAT>         Read the config as json, ron or toml regardless of extension
+                            (persist files always go by their own extension)
+  --dry-run                 Load everything and sign in, but don't connect to the gateway
+  --log-level <LEVEL>       One of error, warn, info (the default) or debug
+  --print-effective-config  Print the merged config with secrets redacted
+  --help                    Print this message";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+  Run,
+  CheckConfig,
+  PrintEffectiveConfig,
+  Setup,
+  PersistDump,
+  PersistImport(PathBuf),
+  PersistMigrate(Format),
+  PersistConvert(PathBuf),
+  ExportRoster,
+  Version,
+  Help
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+  pub config: Option<PathBuf>,
+  pub persist: Option<PathBuf>,
+  pub format: Option<Format>,
+  pub dry_run: bool,
+  pub log_level: Option<LogLevel>
+}
+
+impl Options {
+  pub fn config_path(&self) -> PathBuf {
+    self.config.clone().unwrap_or_else(config::default_path)
+  }
+
+  pub fn persist_path(&self, config_format: Format) -> PathBuf {
+    self.persist.clone().unwrap_or_else(|| persist::default_path(config_format))
+  }
+}
+
+pub fn parse(args: impl IntoIterator<Item = String>) -> Result<(Command, Options), Error> {
+  let mut options = Options::default();
+  let mut words = Vec::new();
+  let mut args = args.into_iter();
+  while let Some(arg) = args.next() {
+    let mut value = |name: &str| args.next()
+      .ok_or_else(|| Error::Invalid(format!("{} requires a value", name)));
+    match arg.as_str() {
+      "--config" => options.config = Some(PathBuf::from(value("--config")?)),
+      "--persist" => options.persist = Some(PathBuf::from(value("--persist")?)),
+      "--format" => options.format = Some(parse_format(&value("--format")?)?),
+      "--dry-run" => options.dry_run = true,
+      "--log-level" => {
+        let level = value("--log-level")?;
+        let level = LogLevel::from_name(&level)
+          .ok_or_else(|| Error::Invalid(format!("unknown log level {}", level)))?;
+        options.log_level = Some(level);
+      },
+      "--print-effective-config" => words.push(arg),
+      "--help" | "-h" => words.push("help".to_owned()),
+      _ if arg.starts_with("--") => return Err(Error::Invalid(format!("unknown option {}", arg))),
+      _ => words.push(arg)
+    };
+  };
+
+  let words = words.iter().map(String::as_str).collect::<Vec<&str>>();
+  let command = match words.as_slice() {
+    [] | ["run"] => Command::Run,
+    ["check-config"] => Command::CheckConfig,
+    ["--print-effective-config"] => Command::PrintEffectiveConfig,
+    ["setup"] => Command::Setup,
+    ["persist", "dump"] => Command::PersistDump,
+    ["persist", "import", file] => Command::PersistImport(PathBuf::from(file)),
+    ["persist", "migrate", format] => Command::PersistMigrate(parse_format(format)?),
+    ["persist", "convert", path] => Command::PersistConvert(PathBuf::from(path)),
+    ["export", "roster"] => Command::ExportRoster,
+    ["version"] => Command::Version,
+    ["help"] => Command::Help,
+    _ => return Err(Error::Invalid(format!("unknown command `{}`", words.join(" "))))
+  };
+
+  Ok((command, options))
+}
+
+pub async fn run(command: Command, options: Options) -> Result<(), Error> {
+  match command {
+    Command::Run => crate::handler::launch(&options).await,
+    Command::CheckConfig => check_config(&options),
+    Command::PrintEffectiveConfig => {
+      println!("{}", config::effective_config(options.config_path(), options.format)?);
+      Ok(())
+    },
+    Command::Setup => crate::setup::run(&options).await,
+    Command::PersistDump => persist_dump(&options),
+    Command::PersistImport(file) => persist_import(&options, file),
+    Command::PersistMigrate(format) => persist_migrate(&options, format),
+    Command::PersistConvert(path) => persist_convert(&options, path),
+    Command::ExportRoster => export_roster(&options).await,
+    Command::Version => {
+      println!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+      Ok(())
+    },
+    Command::Help => {
+      println!("{}", USAGE);
+      Ok(())
+    }
+  }
+}
+
+fn parse_format(name: &str) -> Result<Format, Error> {
+  Format::from_name(name)
+    .ok_or_else(|| Error::Invalid(format!("unknown format {}, expected json, ron or toml", name)))
+}
+
+fn open_config(options: &Options) -> Result<ConfigFile, Error> {
+  let config = ConfigFile::open(options.config_path(), options.format)?;
+  config.check_permissions()?;
+  config.validate()?;
+  Ok(config)
+}
+
+/// Opens the persist store, which is read in the format matching its own extension
+/// whatever `--format` says, since it is often in a different format than the config
+fn open_persist(options: &Options, config_format: Format) -> Result<Box<dyn Store>, Error> {
+  store::open(options.persist_path(config_format), None)
+}
+
+fn check_config(options: &Options) -> Result<(), Error> {
+  let config = open_config(options)?;
+  println!(
+    "{} is valid: {} ranks, {} positions, {} assignables, {} role menu entries",
+    config.path().display(), config.ranks.len(), config.positions.len(),
+    config.assignable.len(), config.role_menu_positions.len()
+  );
+  for key in crate::commands::unknown_permission_keys(&config) {
+    println!("Warning: permission entry `{}` does not match any command or group", key);
+  };
+
+  let persist_path = options.persist_path(config.format());
+  if persist_path.exists() {
+    let persist = open_persist(options, config.format())?;
+    println!("{} is valid: {} greeted users", persist_path.display(), persist.snapshot()?.greeted_users.len());
+  };
+
+  Ok(())
+}
+
+fn persist_dump(options: &Options) -> Result<(), Error> {
+  let config_format = Format::from_path(options.config_path()).unwrap_or(Format::Json);
+  let persist = open_persist(options, config_format)?;
+  let format = options.format.or_else(|| Format::from_path(persist.path())).unwrap_or(Format::Json);
+  println!("{}", format.to_string(&persist.snapshot()?)?);
+  Ok(())
+}
+
+fn persist_import(options: &Options, file: PathBuf) -> Result<(), Error> {
+  let config_format = Format::from_path(options.config_path()).unwrap_or(Format::Json);
+  let source = match store::is_sqlite(&file) {
+    true => store::open(&file, None)?.snapshot()?,
+    false => PersistFile::load(&file, None)?
+  };
+
+  let path = options.persist_path(config_format);
+  let mut persist = store::create_or_default(&path, None)?;
+  persist.replace(source)?;
+  persist.commit()?;
+  println!("Imported {} into {}", file.display(), path.display());
+  Ok(())
+}
+
+fn persist_migrate(options: &Options, format: Format) -> Result<(), Error> {
+  let config_format = Format::from_path(options.config_path()).unwrap_or(Format::Json);
+  let persist = open_persist(options, config_format)?;
+  let new_path = persist.path().with_extension(format.extension());
+  convert_persist(persist, new_path, Some(format))
+}
+
+fn persist_convert(options: &Options, path: PathBuf) -> Result<(), Error> {
+  let config_format = Format::from_path(options.config_path()).unwrap_or(Format::Json);
+  let persist = open_persist(options, config_format)?;
+  convert_persist(persist, path, None)
+}
+
+/// Copies the persist state into a new file or database and moves the old one out of the way
+fn convert_persist(persist: Box<dyn Store>, new_path: PathBuf, format: Option<Format>) -> Result<(), Error> {
+  if new_path == persist.path() {
+    return Err(Error::Custom("the persist state is already stored there"));
+  } else if new_path.exists() {
+    return Err(Error::Invalid(format!("{} already exists", new_path.display())));
+  };
+
+  let new_persist = store::convert(&*persist, &new_path, format)?;
+
+  // Move the old file out of the way so it isn't picked up instead of the new one
+  let old_path = persist.path().to_owned();
+  let file_name = old_path.file_name().map_or("persist".into(), |name| name.to_string_lossy());
+  let backup_path = old_path.with_file_name(format!("{}.bak", file_name));
+  std::mem::drop(persist);
+  fs::rename(&old_path, &backup_path)?;
+  println!(
+    "Converted {} to {} ({}), the old file was moved to {}",
+    old_path.display(), new_path.display(), new_persist.backend(), backup_path.display()
+  );
+
+  Ok(())
+}
+
+async fn export_roster(options: &Options) -> Result<(), Error> {
+  let config = open_config(options)?;
+  let http = Http::new_with_token(config.token.as_deref().unwrap());
+
+  let mut members: Vec<Member> = Vec::new();
+  loop {
+    let after = members.last().map(|member| member.user.id.0);
+    let page = http.get_guild_members(config.guild.0, Some(1000), after).await?;
+    let done = page.len() < 1000;
+    members.extend(page);
+    if done { break };
+  };
+
+  println!("user_id,username,nickname,rank,position");
+  for member in members.iter().filter(|member| !member.user.bot) {
+    let rank = config.get_member_ranks(&member.roles).first().map(|rank| rank.name.as_str());
+    let position = config.get_member_positions(&member.roles).first().map(|position| position.name.as_str());
+    println!(
+      "{},{},{},{},{}",
+      member.user.id,
+      csv_field(&member.user.tag()),
+      csv_field(member.nick.as_deref().unwrap_or("")),
+      csv_field(rank.unwrap_or("")),
+      csv_field(position.unwrap_or(""))
+    );
+  };
+
+  Ok(())
+}
+
+fn csv_field(field: &str) -> String {
+  if field.contains([',', '"', '\n']) {
+    format!("\"{}\"", field.replace('"', "\"\""))
+  } else {
+    field.to_owned()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn parse_args(args: &[&str]) -> Result<(Command, Options), Error> {
+    parse(args.iter().map(|arg| arg.to_string()))
+  }
+
+  fn command(args: &[&str]) -> Command {
+    parse_args(args).unwrap().0
+  }
+
+  #[test]
+  fn subcommands_are_parsed() {
+    assert_eq!(command(&[]), Command::Run);
+    assert_eq!(command(&["run"]), Command::Run);
+    assert_eq!(command(&["check-config"]), Command::CheckConfig);
+    assert_eq!(command(&["setup"]), Command::Setup);
+    assert_eq!(command(&["persist", "dump"]), Command::PersistDump);
+    assert_eq!(command(&["persist", "import", "old.json"]), Command::PersistImport(PathBuf::from("old.json")));
+    assert_eq!(command(&["persist", "migrate", "ron"]), Command::PersistMigrate(Format::Ron));
+    assert_eq!(command(&["persist", "convert", "persist.db"]), Command::PersistConvert(PathBuf::from("persist.db")));
+    assert_eq!(command(&["export", "roster"]), Command::ExportRoster);
+    assert_eq!(command(&["version"]), Command::Version);
+    assert_eq!(command(&["help"]), Command::Help);
+    assert_eq!(command(&["-h"]), Command::Help);
+  }
+
+  #[test]
+  fn options_go_anywhere() {
+    let (command, options) = parse_args(&["persist", "--config", "a.toml", "dump", "--dry-run", "--format", "toml"]).unwrap();
+    assert_eq!(command, Command::PersistDump);
+    assert_eq!(options.config, Some(PathBuf::from("a.toml")));
+    assert_eq!(options.format, Some(Format::Toml));
+    assert!(options.dry_run);
+    assert_eq!(options.log_level, None);
+  }
+
+  #[test]
+  fn print_effective_config_is_a_command() {
+    let (command, options) = parse_args(&["--print-effective-config", "--config", "shared.json"]).unwrap();
+    assert_eq!(command, Command::PrintEffectiveConfig);
+    assert_eq!(options.config, Some(PathBuf::from("shared.json")));
+    assert!(parse_args(&["--print-effective-config", "run"]).is_err());
+  }
+
+  #[test]
+  fn bad_arguments_are_described() {
+    let describe = |args: &[&str]| parse_args(args).unwrap_err().describe();
+    assert_eq!(describe(&["--config"]), "--config requires a value");
+    assert_eq!(describe(&["--verbose"]), "unknown option --verbose");
+    assert_eq!(describe(&["persist", "migrate", "yaml"]), "unknown format yaml, expected json, ron or toml");
+    assert_eq!(describe(&["persist"]), "unknown command `persist`");
+  }
+}
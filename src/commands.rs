@@ -1,55 +1,528 @@
 mod admin;
+mod config;
 mod general;
 mod owner;
+mod resolve;
+
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
 
 use serenity::{
   prelude::*,
   framework::standard::{
-    Args, CommandOptions, Reason,
+    Args, Command, CommandGroup, CommandOptions, CommandResult, Reason,
     macros::*
   },
   model::{
-    id::UserId,
+    id::{GuildId, RoleId, UserId},
     channel::Message,
     guild::Member
   }
 };
 
-use crate::data::config::ConfigContainer;
+use crate::data::config::{Config, ConfigContainer, ConfigFile, Permission};
 use crate::handler::*;
-use crate::util::ResultExt;
+use crate::history::{Change, HistoryContainer};
+use crate::metrics::MetricsContainer;
+use crate::queue::MemberQueueContainer;
+use crate::shutdown::ShutdownContainer;
+use crate::util::{ResultExt, truncate};
 
 pub mod groups {
   pub use super::admin::ADMIN_GROUP;
+  pub use super::config::CONFIGEDIT_GROUP;
   pub use super::general::GENERAL_GROUP;
   pub use super::owner::OWNER_GROUP;
+
+  use serenity::framework::standard::CommandGroup;
+
+  /// Every command group, in the order they are registered
+  pub static ALL: &[&CommandGroup] = &[&OWNER_GROUP, &CONFIGEDIT_GROUP, &ADMIN_GROUP, &GENERAL_GROUP];
 }
 
+pub use self::owner::{change_rank, Scheme as RankScheme};
+
+// Checks the permission table in the config, applied to every group (a plain comment, since
+// `#[check]` turns doc comments into a `description` attribute it doesn't accept)
 #[check]
-#[name = "admin"]
-async fn admin_check(ctx: &Context, msg: &Message, _: &mut Args, _: &CommandOptions) -> Result<(), Reason> {
-  // Is the user on the list of owners?
-  let config = data_get::<ConfigContainer>(&ctx).await;
-  let config_lock = config.read().await;
-  if config_lock.owners.contains(&msg.author.id) { return Ok(()) };
+#[name = "permitted"]
+async fn permitted_check(ctx: &Context, msg: &Message, _: &mut Args, options: &CommandOptions) -> Result<(), Reason> {
+  let config = data_get::<ConfigContainer>(ctx).await.load();
+  let entry = CommandEntry::find(options)
+    .ok_or_else(|| Reason::Log("Command is not in any group".to_string()))?;
+  let (permission, _) = entry.permission(&config);
+  match is_permitted(ctx, &config, msg, &permission).await {
+    true => Ok(()),
+    false => Err(Reason::User("Insufficient permissions".to_string()))
+  }
+}
+
+async fn is_permitted(ctx: &Context, config: &ConfigFile, msg: &Message, permission: &Permission) -> bool {
+  if permission.everyone || config.owners.contains(&msg.author.id) || permission.users.contains(&msg.author.id) {
+    return true;
+  };
+
+  // Roles and permissions only count in the configured guild
+  if !config.is_configured_guild(msg.guild_id) { return false };
+  let member = match msg.member(&ctx).await {
+    Ok(member) => member,
+    Err(_) => return false
+  };
+
+  if config.member_matches(permission, &member.roles) { return true };
+  permission.admins && member.permissions(&ctx).await.is_ok_and(|permissions| permissions.administrator())
+}
+
+/// Keys in the permission table that don't name any command or group
+pub fn unknown_permission_keys(config: &Config) -> Vec<&str> {
+  config.permissions.keys()
+    .filter(|&key| {
+      !groups::ALL.iter().any(|group| group.name.to_lowercase() == *key) &&
+      !CommandEntry::all().any(|entry| entry.key() == *key)
+    })
+    .map(String::as_str)
+    .collect()
+}
+
+/// A top level command along with the group it is in
+struct CommandEntry {
+  group: &'static CommandGroup,
+  command: &'static Command
+}
+
+impl CommandEntry {
+  /// Every top level command, in the order they are registered
+  fn all() -> impl Iterator<Item = CommandEntry> {
+    groups::ALL.iter().flat_map(|&group| {
+      group.options.commands.iter().map(move |&command| CommandEntry { group, command })
+    })
+  }
+
+  /// Finds the top level command a command or sub-command belongs to
+  fn find(options: &CommandOptions) -> Option<CommandEntry> {
+    fn contains(command: &Command, options: &CommandOptions) -> bool {
+      std::ptr::eq(command.options, options) ||
+      command.options.sub_commands.iter().any(|sub_command| contains(sub_command, options))
+    }
+
+    CommandEntry::all().find(|entry| contains(entry.command, options))
+  }
+
+  /// Finds a command by how it's invoked, like `setrank`, `config rank` or `backup now`
+  fn find_by_invocation(invocation: &str) -> Option<CommandEntry> {
+    let invocation = invocation.trim().trim_start_matches('$').to_lowercase();
+    CommandEntry::all().find(|entry| {
+      entry.command.options.names.iter().any(|&name| {
+        let key = entry.key_for(name);
+        invocation == key || invocation.starts_with(&format!("{} ", key))
+      })
+    })
+  }
+
+  /// The command's key in the permission table
+  fn key(&self) -> String {
+    self.key_for(self.command.options.names[0])
+  }
+
+  fn key_for(&self, name: &str) -> String {
+    match self.group.options.prefixes.first() {
+      Some(prefix) => format!("{} {}", prefix, name),
+      None => name.to_owned()
+    }
+  }
+
+  /// The group's key in the permission table
+  fn group_key(&self) -> String {
+    self.group.name.to_lowercase()
+  }
+
+  /// Who may run the command, and which entry that came from
+  fn permission(&self, config: &Config) -> (Permission, String) {
+    let (key, group_key) = (self.key(), self.group_key());
+    if let Some(permission) = config.permissions.get(&key) {
+      return (permission.clone(), format!("`{}` entry", key));
+    };
+
+    if let Some(permission) = config.permissions.get(&group_key) {
+      return (permission.clone(), format!("`{}` group entry", group_key));
+    };
+
+    let permission = match self.group.name {
+      "Admin" => Permission::admins(),
+      "General" => Permission::everyone(),
+      _ => Permission::default()
+    };
+
+    (permission, "default".to_owned())
+  }
+}
+
+#[hook]
+pub async fn before_command(ctx: &Context, msg: &Message, command_name: &str) -> bool {
+  // Ignore commands from other guilds, and direct messages from anyone but owners
+  let config = data_get::<ConfigContainer>(ctx).await.load();
+  let allowed = match msg.guild_id {
+    Some(_) => config.is_configured_guild(msg.guild_id),
+    None => config.owners.contains(&msg.author.id)
+  };
+
+  if !allowed {
+    log!(Debug, "Ignoring command {} from {} outside the configured guild", command_name, msg.author.id);
+    return false;
+  };
+
+  // Refuse new commands while shutting down
+  data_get::<ShutdownContainer>(ctx).await.enter()
+}
+
+#[hook]
+pub async fn after_command(ctx: &Context, _: &Message, command_name: &str, result: CommandResult) {
+  data_get::<ShutdownContainer>(ctx).await.exit();
+  let metrics = data_get::<MetricsContainer>(ctx).await;
+  match result {
+    Ok(()) => metrics.record_command(command_name, "success"),
+    Err(err) => {
+      if let Some(err) = err.downcast_ref::<SerenityError>() {
+        metrics.record_api_error(err);
+      };
+
+      log!(Error, "Command {} failed: {:?}", command_name, err);
+      metrics.record_command(command_name, "error");
+    }
+  };
+}
+
+/// Reads a single member from the arguments, see `resolve::resolve_member`
+async fn get_member_from_args(ctx: &Context, msg: &Message, args: &mut Args) -> Result<Member, String> {
+  let query = args.single_quoted::<String>()
+    .map_err(|_| "No member given".to_owned())?;
+  resolve::resolve_member(ctx, msg, msg.guild_id.unwrap(), &query).await
+}
+
+/// Reads the members to act on from the front of the arguments: members (see
+/// `resolve::resolve_member`) and `@everyone-in:<role>` selectors.
+///
+/// Commands that take more arguments after the members set `trailing`, in which case only the
+/// first member may be given by name; after that, reading stops at the first argument that isn't
+/// a mention, id, `name#discriminator` or selector. Selectors go by the full member list fetched
+/// from Discord, and the author has to confirm if they select over `SELECTOR_CONFIRM` members.
+/// Fails with a message for the user if nobody matched or a member couldn't be found.
+async fn get_members_from_args(ctx: &Context, msg: &Message, config: &ConfigFile, args: &mut Args, trailing: bool) -> Result<Vec<Member>, String> {
+  let guild_id = msg.guild_id.unwrap();
+  let mut members = Vec::new();
+  let mut fetched: Option<Vec<Member>> = None;
+  let mut selected = HashSet::new();
+  let mut targets = 0;
+  args.quoted();
+  while let Some(arg) = args.current().map(str::to_owned) {
+    if let Some(selector) = arg.strip_prefix(SELECTOR_PREFIX) {
+      let role = selector_role(config, selector)
+        .ok_or_else(|| format!("`{}` isn't a position, rank, assignable or role", selector))?;
+      // The cache only has the members the bot has seen, so fetch them all, once
+      if fetched.is_none() {
+        let all_members = fetch_members(ctx, guild_id).await
+          .map_err(|err| format!("Couldn't fetch the members of the server: {}", err))?;
+        fetched = Some(all_members);
+      };
+
+      let matched = fetched.iter().flatten()
+        .filter(|member| !member.user.bot && member.roles.contains(&role));
+      for member in matched {
+        selected.insert(member.user.id);
+        members.push(member.clone());
+      };
+    } else if !trailing || targets == 0 || resolve::is_exact(&arg) {
+      members.push(resolve::resolve_member(ctx, msg, guild_id, &arg).await?);
+    } else {
+      break;
+    };
+
+    targets += 1;
+    args.advance();
+  };
+
+  // The same member may be picked more than once, by name and by selector
+  let mut seen = HashSet::new();
+  members.retain(|member| seen.insert(member.user.id));
+  if members.is_empty() {
+    return Err("No members matched".to_owned());
+  };
+
+  if selected.len() > SELECTOR_CONFIRM {
+    let question = format!("The selectors pick {} members, reply `yes` to go ahead", selected.len());
+    if !resolve::confirm(ctx, msg, &question).await? {
+      return Err("Cancelled".to_owned());
+    };
+  };
+
+  Ok(members)
+}
+
+/// Fetches every member of a guild from Discord, a page at a time
+async fn fetch_members(ctx: &Context, guild_id: GuildId) -> Result<Vec<Member>, SerenityError> {
+  let mut members: Vec<Member> = Vec::new();
+  loop {
+    let after = members.last().map(|member| member.user.id);
+    let page = guild_id.members(&ctx.http, Some(1000), after).await?;
+    let done = page.len() < 1000;
+    members.extend(page);
+    if done { break Ok(members) };
+  }
+}
+
+/// Selects every member with a role, like `@everyone-in:Rifleman`
+const SELECTOR_PREFIX: &str = "@everyone-in:";
+/// Selectors that pick more members than this have to be confirmed
+const SELECTOR_CONFIRM: usize = 10;
+
+/// The role a selector names: a rank, assignable or position by name, or a role mention or id
+fn selector_role(config: &ConfigFile, name: &str) -> Option<RoleId> {
+  config.get_rank_by_name_loose(name).map(|rank| rank.role)
+    .or_else(|| config.get_assignable_loose(name))
+    .or_else(|| {
+      config.positions.iter()
+        .find(|position| position.name.eq_ignore_ascii_case(name))
+        .map(|position| position.role)
+    })
+    .or_else(|| name.parse::<RoleId>().ok())
+}
+
+/// What a command wants to do to a member's roles
+pub enum RoleChange {
+  /// Replace the member's roles with these
+  Set(HashSet<RoleId>),
+  /// The change doesn't apply to the member
+  Skip,
+  /// Whoever ran the command isn't allowed to make the change, and why
+  Deny(String)
+}
+
+/// How far up the rank ladder whoever ran a command is, which limits the ranks they may hand out
+pub enum Authority {
+  /// Owners, and the bot itself when carrying out an approved recommendation
+  Owner,
+  Rank(usize),
+  Unranked
+}
+
+impl Authority {
+  async fn of(ctx: &Context, msg: &Message, config: &ConfigFile) -> Authority {
+    if config.owners.contains(&msg.author.id) { return Authority::Owner };
+    match msg.member(&ctx).await {
+      Ok(member) => config.get_highest_rank(&member.roles)
+        .map_or(Authority::Unranked, |(index, _)| Authority::Rank(index)),
+      Err(_) => Authority::Unranked
+    }
+  }
+
+  /// Checks that the rank at `index` is strictly below the actor's own rank
+  fn check_above(&self, config: &ConfigFile, index: usize, action: &str) -> Result<(), String> {
+    match self {
+      Authority::Owner => Ok(()),
+      Authority::Rank(own) if *own > index => Ok(()),
+      Authority::Rank(_) | Authority::Unranked => {
+        Err(format!("{} requires a rank above {}", action, config.ranks[index].name))
+      }
+    }
+  }
+}
+
+/// How a queued role change turned out
+enum Outcome {
+  /// The change was made, with the member's roles before and after it
+  Changed(HashSet<RoleId>, HashSet<RoleId>),
+  /// An identical change was already waiting, so this one was merged into it
+  Merged,
+  /// The change didn't apply to the member
+  Skipped,
+  Denied(String),
+  Failed(SerenityError)
+}
+
+/// Changes a member's roles through their action queue.
+///
+/// `f` is given the member as they are once it's their turn, and works out the change to make.
+/// If `key` is given, an identical change with the same key that is already queued takes this one's
+/// place; only pass one for changes that end the same however many times they're made.
+async fn queue_role_change<F>(ctx: &Context, guild_id: GuildId, user_id: UserId, key: Option<String>, f: F) -> Outcome
+where F: FnOnce(&Member) -> RoleChange + Send {
+  let queue = data_get::<MemberQueueContainer>(ctx).await;
+  let result = queue.run(guild_id, user_id, key, || async move {
+    let member = ctx.http.get_member(guild_id.0, user_id.0).await?;
+    let change = f(&member);
+    if let RoleChange::Set(roles) = &change {
+      member.edit(&ctx, |edit| edit.roles(roles.iter())).await?;
+    };
+
+    let before = member.roles.iter().cloned().collect::<HashSet<RoleId>>();
+    Ok::<(HashSet<RoleId>, RoleChange), SerenityError>((before, change))
+  }).await;
+
+  match result {
+    Some(Ok((before, RoleChange::Set(after)))) => Outcome::Changed(before, after),
+    None => Outcome::Merged,
+    Some(Ok((_, RoleChange::Skip))) => Outcome::Skipped,
+    Some(Ok((_, RoleChange::Deny(reason)))) => Outcome::Denied(reason),
+    Some(Err(err)) => Outcome::Failed(err)
+  }
+}
+
+/// Makes the same kind of role change to every member in turn, then reports how it went.
+///
+/// A single member gets a reaction, along with the reason if the change was refused. Several
+/// members get a progress message that is kept up to date and ends with a line for each of them.
+/// The changes are recorded in the history as one action, so they can be undone together.
+/// `key` is passed on to `queue_role_change`, so relative changes like promotions must leave it out.
+async fn change_roles<F>(ctx: &Context, msg: &Message, verb: &str, members: Vec<Member>, key: Option<&str>, f: F)
+where F: Fn(&Member) -> RoleChange + Send + Sync {
+  let history = data_get::<HistoryContainer>(ctx).await;
+  let action = history.new_action();
+  let record = |member: &Member, before, after| {
+    let description = format!("{} {}", verb, member.display_name());
+    history.record(action, member.guild_id, member.user.id, msg.author.id, description, before, after);
+  };
 
-  if let Ok(member) = msg.member(&ctx).await {
-    // Does the user have an administrator role?
-    let has_admin_role = member.roles.iter()
-      .any(|&role| config_lock.is_admin_role(role));
-    if has_admin_role { return Ok(()) };
+  if let [member] = members.as_slice() {
+    match queue_role_change(ctx, member.guild_id, member.user.id, key.map(str::to_owned), &f).await {
+      Outcome::Changed(before, after) => {
+        record(member, before, after);
+        react_success(ctx, msg).await;
+      },
+      Outcome::Merged => {
+        let text = format!("Merged into the same change for {}, which was already waiting", member.display_name());
+        msg.reply(&ctx, text).await.report_with("Failed to send message");
+      },
+      Outcome::Denied(reason) => reply_failure(ctx, msg, &reason).await,
+      Outcome::Skipped | Outcome::Failed(_) => react_failure(ctx, msg).await
+    };
 
-    // Does the user have the administrator permission?
-    let permissions = member.permissions(&ctx).await.unwrap();
-    if permissions.administrator() { return Ok(()) };
+    return;
   };
 
-  Err(Reason::User("Insufficient permissions".to_string()))
+  let total = members.len();
+  let mut progress = match msg.reply(&ctx, format!("{} {} members...", verb, total)).await {
+    Ok(progress) => Some(progress),
+    Err(err) => {
+      log!(Error, "Failed to send message: {:?}", err);
+      None
+    }
+  };
+  let mut last_update = Instant::now();
+  let mut lines = Vec::with_capacity(total);
+  let mut failed = 0;
+  let mut merged = 0;
+
+  // One member at a time, so the bulk change waits its turn with the rate limiter like anything else
+  for (done, member) in members.iter().enumerate() {
+    let name = member.display_name();
+    let problem = match queue_role_change(ctx, member.guild_id, member.user.id, key.map(str::to_owned), &f).await {
+      Outcome::Changed(before, after) => {
+        record(member, before, after);
+        lines.push(format!("\u{2705} {}", name));
+        None
+      },
+      Outcome::Merged => {
+        merged += 1;
+        lines.push(format!("\u{1f501} {}: merged into the same change, which was already waiting", name));
+        None
+      },
+      Outcome::Skipped => Some("nothing to change".to_owned()),
+      Outcome::Denied(reason) => Some(reason),
+      Outcome::Failed(err) => Some(err.to_string())
+    };
+
+    if let Some(problem) = problem {
+      failed += 1;
+      lines.push(format!("\u{274e} {}: {}", name, problem));
+    };
+
+    if let Some(progress) = progress.as_mut() {
+      if last_update.elapsed() >= PROGRESS_INTERVAL && done + 1 < total {
+        let content = format!("{} {} members: {}/{} done, {} failed", verb, total, done + 1, total, failed);
+        progress.edit(&ctx, |edit| edit.content(content)).await.report();
+        last_update = Instant::now();
+      };
+    };
+  };
+
+  let summary = format!(
+    "{} {} members: {} succeeded, {} merged, {} failed\n{}",
+    verb, total, total - failed - merged, merged, failed, lines.join("\n")
+  );
+
+  match progress.as_mut() {
+    Some(progress) => progress.edit(&ctx, |edit| edit.content(truncate(&summary, 1900))).await.report(),
+    None => msg.reply(&ctx, truncate(&summary, 1900)).await.map(|_| ()).report()
+  };
+
+  // Merged changes were made by someone else's command, so they don't count towards this one's success
+  match (failed, merged) {
+    (0, merged) if merged < total => react_success(ctx, msg).await,
+    (0, _) => (),
+    _ => react_failure(ctx, msg).await
+  };
+}
+
+/// Puts back the roles a recorded change added or removed.
+///
+/// Refuses if any of those roles have changed hands since, and holds undoing to the same rank
+/// ladder as making the change did.
+fn revert_change(config: &ConfigFile, authority: &Authority, member: &Member, change: &Change) -> RoleChange {
+  let current: HashSet<RoleId> = member.roles.iter().cloned().collect();
+  if change.added().any(|role| !current.contains(role)) || change.removed().any(|role| current.contains(role)) {
+    return RoleChange::Deny(format!("{}'s roles have changed since then", member.display_name()));
+  };
+
+  let action = format!("Undoing \"{}\"", change.description);
+  for &role in change.removed() {
+    if let Some((_, rank)) = config.get_rank_by_role(role) {
+      if config.requires_review(&rank.name) && !matches!(authority, Authority::Owner) {
+        return RoleChange::Deny(format!("{} can only be given through `$recommend`", rank.name));
+      };
+    };
+  };
+
+  let highest = change.added().chain(change.removed())
+    .filter_map(|&role| config.get_rank_by_role(role).or_else(|| config.get_assignable_restriction(role)))
+    .map(|(index, _)| index)
+    .max();
+  if let Some(index) = highest {
+    if let Err(reason) = authority.check_above(config, index, &action) {
+      return RoleChange::Deny(reason);
+    };
+  };
+
+  let mut roles = current.clone();
+  for role in change.added() {
+    roles.remove(role);
+  };
+  roles.extend(change.removed().cloned());
+
+  match roles == current {
+    true => RoleChange::Skip,
+    false => RoleChange::Set(roles)
+  }
+}
+
+/// How often a bulk change's progress message may be edited
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(2);
+
+/// A member's name for use in messages, without mentioning them
+async fn display_name(ctx: &Context, guild_id: GuildId, user_id: UserId) -> String {
+  match ctx.cache.member(guild_id, user_id).await {
+    Some(member) => member.display_name().into_owned(),
+    None => user_id.to_string()
+  }
+}
+
+async fn reply_usage(ctx: &Context, msg: &Message, usage: &str) -> CommandResult {
+  reply_failure(ctx, msg, &format!("Usage: `${}`", usage)).await;
+  Ok(())
 }
 
-async fn get_member_from_args(ctx: &Context, msg: &Message, args: &mut Args) -> Option<Member> {
-  let member = args.single::<UserId>().ok()?;
-  ctx.cache.member(msg.guild_id.unwrap(), member).await
+async fn reply_failure(ctx: &Context, msg: &Message, text: &str) {
+  msg.reply(&ctx, text).await.report_with("Failed to send message");
+  react_failure(ctx, msg).await;
 }
 
 async fn react_success(ctx: &Context, msg: &Message) {
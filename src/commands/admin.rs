@@ -1,3 +1,5 @@
+use std::collections::HashSet;
+
 use serenity::{
   prelude::*,
   framework::standard::{
@@ -7,69 +9,243 @@ use serenity::{
   model::{
     id::RoleId,
     guild::Member,
-    channel::Message
+    channel::{Message, ReactionType}
   }
 };
 
-use crate::data::config::{Config, ConfigContainer};
+use crate::data::config::{ConfigContainer, ConfigFile};
+use crate::data::recommend::Status;
 use crate::handler::*;
+use crate::history::HistoryContainer;
+use crate::recommend::{APPROVE, DENY, RecommendationsContainer};
+use crate::util::{ResultExt, truncate};
 use super::*;
 
 #[group]
-#[commands(assign, unassign)]
+#[checks(permitted)]
+#[commands(assign, unassign, undo, recommend, recommendations)]
 struct Admin;
 
 #[command]
 #[only_in(guilds)]
-#[checks(admin)]
 async fn assign(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
-  let config = data_get::<ConfigContainer>(&ctx).await;
-  let config_lock = config.read().await;
-  if let Some(mut member) = get_member_from_args(&ctx, &msg, &mut args).await {
-    if let Some(role) = change_assignable(&config_lock, &member, Scheme::Assign(args.rest())) {
-      match member.add_role(&ctx, role).await {
-        Ok(_) => react_success(&ctx, &msg).await,
-        Err(_) => react_failure(&ctx, &msg).await
-      };
-    } else {
-      react_failure(&ctx, &msg).await;
-    };
-  } else {
-    react_failure(&ctx, &msg).await;
+  let config = data_get::<ConfigContainer>(ctx).await.load();
+  match get_members_from_args(ctx, msg, &config, &mut args, true).await {
+    Ok(members) => {
+      let authority = Authority::of(ctx, msg, &config).await;
+      let key = format!("assign:{}", args.rest().to_lowercase());
+      let verb = format!("Assigning {} to", args.rest());
+      let scheme = Scheme::Assign(args.rest());
+      change_roles(ctx, msg, &verb, members, Some(&key), |member| change_assignable(&config, &authority, member, scheme)).await;
+    },
+    Err(reason) => reply_failure(ctx, msg, &reason).await
   };
   
   Ok(())
 }
 
 #[command]
+#[only_in(guilds)]
 async fn unassign(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
-  let config = data_get::<ConfigContainer>(&ctx).await;
-  let config_lock = config.read().await;
-  if let Some(mut member) = get_member_from_args(&ctx, &msg, &mut args).await {
-    if let Some(role) = change_assignable(&config_lock, &member, Scheme::Unassign(args.rest())) {
-      match member.remove_role(&ctx, role).await {
-        Ok(_) => react_success(&ctx, &msg).await,
-        Err(_) => react_failure(&ctx, &msg).await
-      };
-    } else {
-      react_failure(&ctx, &msg).await;
-    };
-  } else {
-    react_failure(&ctx, &msg).await;
+  let config = data_get::<ConfigContainer>(ctx).await.load();
+  match get_members_from_args(ctx, msg, &config, &mut args, true).await {
+    Ok(members) => {
+      let authority = Authority::of(ctx, msg, &config).await;
+      let key = format!("unassign:{}", args.rest().to_lowercase());
+      let verb = format!("Unassigning {} from", args.rest());
+      let scheme = Scheme::Unassign(args.rest());
+      change_roles(ctx, msg, &verb, members, Some(&key), |member| change_assignable(&config, &authority, member, scheme)).await;
+    },
+    Err(reason) => reply_failure(ctx, msg, &reason).await
   };
   
   Ok(())
 }
 
-fn change_assignable(config: &Config, member: &Member, scheme: Scheme<'_>) -> Option<RoleId> {
-  let role = config.get_assignable_loose(scheme.as_str())?;
-  match (scheme, member.roles.contains(&role)) {
-    (Scheme::Assign(_), true) => None,
-    (Scheme::Unassign(_), false) => None,
-    _ => Some(role)
+/// `$undo [user]`, reverts the last role change you made, or the last one made to a member
+#[command]
+#[only_in(guilds)]
+async fn undo(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+  let config = data_get::<ConfigContainer>(ctx).await.load();
+  let history = data_get::<HistoryContainer>(ctx).await;
+  let changes = match args.is_empty() {
+    true => history.last_action_by(msg.author.id),
+    false => match get_member_from_args(ctx, msg, &mut args).await {
+      Ok(member) => history.last_for(member.guild_id, member.user.id).into_iter().collect(),
+      Err(reason) => {
+        reply_failure(ctx, msg, &reason).await;
+        return Ok(());
+      }
+    }
+  };
+
+  if changes.is_empty() {
+    reply_failure(ctx, msg, "Nothing to undo").await;
+    return Ok(());
+  };
+
+  let authority = Authority::of(ctx, msg, &config).await;
+  let mut lines = Vec::with_capacity(changes.len());
+  let mut failed = 0;
+  let mut undone = 0;
+  for change in changes.iter() {
+    // Undoing is relative to the member's current roles, so it's never merged with anything
+    let outcome = queue_role_change(ctx, change.guild_id, change.user_id, None, |member| {
+      revert_change(&config, &authority, member, change)
+    }).await;
+    // Only a change that was actually reverted counts as undone, so it can't be undone twice
+    let problem = match outcome {
+      Outcome::Changed(..) => {
+        undone += 1;
+        history.mark_undone(change.id);
+        lines.push(format!("\u{2705} {}", change.description));
+        None
+      },
+      Outcome::Skipped => {
+        lines.push(format!("\u{2796} {}: nothing to undo", change.description));
+        None
+      },
+      Outcome::Merged => Some("merged into the same change, which was already waiting".to_owned()),
+      Outcome::Denied(reason) => Some(reason),
+      Outcome::Failed(err) => Some(err.to_string())
+    };
+
+    if let Some(problem) = problem {
+      failed += 1;
+      lines.push(format!("\u{274e} {}: {}", change.description, problem));
+    };
+  };
+
+  // A single change only gets a reply when it wasn't undone, like any other role change
+  if changes.len() > 1 || undone == 0 {
+    msg.reply(&ctx, truncate(&lines.join("\n"), 1900)).await.report_with("Failed to send message");
+  };
+
+  match (failed, undone) {
+    (0, 0) => (),
+    (0, _) => react_success(ctx, msg).await,
+    _ => react_failure(ctx, msg).await
+  };
+
+  Ok(())
+}
+
+/// `$recommend <user> <rank> <reason...>`, puts a member up for a rank on a ballot in the review channel
+#[command]
+#[only_in(guilds)]
+async fn recommend(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+  let config = data_get::<ConfigContainer>(ctx).await.load();
+  let review = match &config.review {
+    Some(review) => review,
+    None => {
+      msg.reply(&ctx, "Recommendations are not set up").await.report_with("Failed to send message");
+      react_failure(ctx, msg).await;
+      return Ok(());
+    }
+  };
+
+  let member = match get_member_from_args(ctx, msg, &mut args).await {
+    Ok(member) => member,
+    Err(reason) => {
+      reply_failure(ctx, msg, &reason).await;
+      return Ok(());
+    }
+  };
+
+  let rank = args.single_quoted::<String>().ok()
+    .and_then(|rank_name| config.get_rank_by_name_loose(&rank_name));
+  let reason = args.rest().trim();
+  let rank = match rank {
+    Some(rank) if !reason.is_empty() => rank,
+    _ => return reply_usage(ctx, msg, "recommend <user> <rank> <reason...>").await
+  };
+
+  let recommendations = data_get::<RecommendationsContainer>(ctx).await;
+  let id = recommendations.file(member.user.id, rank.name.clone(), reason.to_owned(), msg.author.id);
+  let recommender = display_name(ctx, member.guild_id, msg.author.id).await;
+  let ballot = format!(
+    "**Recommendation #{}**: {} for {}, by {}\n> {}\nNeeds {} votes from {} or above. React {} to approve or {} to deny.",
+    id, member.display_name(), rank.name, recommender, reason,
+    review.approvals, review.approver_rank, APPROVE, DENY
+  );
+
+  let ballot = match review.channel.say(&ctx, ballot).await {
+    Ok(ballot) => ballot,
+    Err(err) => {
+      log!(Error, "Couldn't post ballot: {:?}", err);
+      recommendations.withdraw(id);
+      react_failure(ctx, msg).await;
+      return Ok(());
+    }
+  };
+
+  recommendations.set_ballot(id, (ballot.channel_id, ballot.id));
+  for &emoji in [APPROVE, DENY].iter() {
+    ballot.react(&ctx, ReactionType::Unicode(emoji.to_owned())).await.report();
+  };
+
+  react_success(ctx, msg).await;
+  Ok(())
+}
+
+/// `$recommendations [all]`, lists open recommendations, or every recommendation
+#[command]
+#[only_in(guilds)]
+async fn recommendations(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
+  let guild_id = msg.guild_id.unwrap();
+  let show_all = args.rest().trim().eq_ignore_ascii_case("all");
+  let mut items = data_get::<RecommendationsContainer>(ctx).await.items();
+  items.retain(|item| show_all || item.status == Status::Open);
+  if items.is_empty() {
+    msg.reply(&ctx, "There are no recommendations").await.report_with("Failed to send message");
+    return Ok(());
+  };
+
+  let mut report = String::new();
+  for item in items.iter().rev() {
+    report.push_str(&format!(
+      "`#{}` {} for {} ({}, {} for, {} against), by {}: {}\n",
+      item.id,
+      display_name(ctx, guild_id, item.user_id).await,
+      item.rank,
+      item.status,
+      item.approvals.len(),
+      item.denials.len(),
+      display_name(ctx, guild_id, item.recommender).await,
+      item.reason
+    ));
+  };
+
+  msg.reply(&ctx, truncate(&report, 1900)).await.report_with("Failed to send message");
+  Ok(())
+}
+
+fn change_assignable(config: &ConfigFile, authority: &Authority, member: &Member, scheme: Scheme<'_>) -> RoleChange {
+  let role = match config.get_assignable_loose(scheme.as_str()) {
+    Some(role) => role,
+    None => return RoleChange::Skip
+  };
+
+  if let Some((index, _)) = config.get_assignable_restriction(role) {
+    let action = match scheme {
+      Scheme::Assign(name) => format!("Assigning {}", name),
+      Scheme::Unassign(name) => format!("Unassigning {}", name)
+    };
+
+    if let Err(reason) = authority.check_above(config, index, &action) {
+      return RoleChange::Deny(reason);
+    };
+  };
+
+  let mut roles: HashSet<RoleId> = member.roles.iter().cloned().collect();
+  match scheme {
+    Scheme::Assign(_) if roles.insert(role) => RoleChange::Set(roles),
+    Scheme::Unassign(_) if roles.remove(&role) => RoleChange::Set(roles),
+    _ => RoleChange::Skip
   }
 }
 
+#[derive(Clone, Copy)]
 enum Scheme<'a> {
   Assign(&'a str),
   Unassign(&'a str)
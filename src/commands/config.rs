@@ -0,0 +1,212 @@
+use serenity::{
+  prelude::*,
+  framework::standard::{
+    Args, CommandResult,
+    macros::*
+  },
+  model::{
+    id::RoleId,
+    channel::Message
+  }
+};
+
+use crate::data::config::{Config, ConfigContainer, Rank};
+use crate::data::member_roles;
+use crate::error::Error;
+use crate::handler::*;
+use crate::util::{ResultExt, truncate};
+use super::*;
+
+#[group]
+#[checks(permitted)]
+#[prefixes("config")]
+#[commands(rank, position, assignable, greeting)]
+struct ConfigEdit;
+
+#[command]
+#[sub_commands(rank_add, rank_remove, rank_move)]
+async fn rank(ctx: &Context, msg: &Message) -> CommandResult {
+  react_failure(ctx, msg).await;
+  Ok(())
+}
+
+/// `$config rank add <name> <role> [position]`, position counts from the lowest rank starting at 1
+#[command("add")]
+async fn rank_add(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+  let (name, role) = match (args.single_quoted::<String>(), args.single::<RoleId>()) {
+    (Ok(name), Ok(role)) => (name, role),
+    _ => return reply_usage(ctx, msg, "config rank add <name> <role> [position]").await
+  };
+
+  let index = args.single::<usize>().ok();
+  apply(ctx, msg, move |config| {
+    if config.ranks.iter().any(|rank| rank.name == name) {
+      return Err(Error::Invalid(format!("rank `{}` already exists", name)));
+    };
+
+    let index = index.map_or(config.ranks.len(), |index| index.saturating_sub(1).min(config.ranks.len()));
+    config.ranks.insert(index, Rank { name, role });
+    Ok(())
+  }).await
+}
+
+/// `$config rank remove <name>`
+#[command("remove")]
+async fn rank_remove(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+  let name = match args.single_quoted::<String>() {
+    Ok(name) => name,
+    Err(_) => return reply_usage(ctx, msg, "config rank remove <name>").await
+  };
+
+  apply(ctx, msg, move |config| {
+    let index = rank_index(config, &name)?;
+    config.ranks.remove(index);
+    Ok(())
+  }).await
+}
+
+/// `$config rank move <name> <position>`, position counts from the lowest rank starting at 1
+#[command("move")]
+async fn rank_move(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+  let (name, index) = match (args.single_quoted::<String>(), args.single::<usize>()) {
+    (Ok(name), Ok(index)) => (name, index),
+    _ => return reply_usage(ctx, msg, "config rank move <name> <position>").await
+  };
+
+  apply(ctx, msg, move |config| {
+    let old_index = rank_index(config, &name)?;
+    let rank = config.ranks.remove(old_index);
+    let index = index.saturating_sub(1).min(config.ranks.len());
+    config.ranks.insert(index, rank);
+    Ok(())
+  }).await
+}
+
+#[command]
+#[sub_commands(position_set)]
+async fn position(ctx: &Context, msg: &Message) -> CommandResult {
+  react_failure(ctx, msg).await;
+  Ok(())
+}
+
+/// `$config position set <name> ranked|admin <true|false>`
+#[command("set")]
+async fn position_set(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+  let usage = "config position set <name> ranked|admin <true|false>";
+  let (name, flag, value) = match (args.single_quoted::<String>(), args.single::<String>(), args.single::<bool>()) {
+    (Ok(name), Ok(flag), Ok(value)) => (name, flag, value),
+    _ => return reply_usage(ctx, msg, usage).await
+  };
+
+  apply(ctx, msg, move |config| {
+    let position = config.positions.iter_mut()
+      .find(|position| position.name == name)
+      .ok_or_else(|| Error::Invalid(format!("position `{}` does not exist", name)))?;
+    match flag.to_lowercase().as_str() {
+      "ranked" => position.ranked = value,
+      "admin" => position.admin = value,
+      _ => return Err(Error::Invalid(format!("unknown flag `{}`, expected ranked or admin", flag)))
+    };
+
+    Ok(())
+  }).await
+}
+
+#[command]
+#[sub_commands(assignable_add, assignable_remove)]
+async fn assignable(ctx: &Context, msg: &Message) -> CommandResult {
+  react_failure(ctx, msg).await;
+  Ok(())
+}
+
+/// `$config assignable add <name> <role>`
+#[command("add")]
+async fn assignable_add(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+  let (name, role) = match (args.single_quoted::<String>(), args.single::<RoleId>()) {
+    (Ok(name), Ok(role)) => (name, role),
+    _ => return reply_usage(ctx, msg, "config assignable add <name> <role>").await
+  };
+
+  apply(ctx, msg, move |config| {
+    let lower_name = name.to_lowercase();
+    if config.assignable.keys().any(|assignable| assignable.to_lowercase() == lower_name) {
+      return Err(Error::Invalid(format!("assignable `{}` already exists", name)));
+    };
+
+    config.assignable.insert(name, role);
+    Ok(())
+  }).await
+}
+
+/// `$config assignable remove <name>`
+#[command("remove")]
+async fn assignable_remove(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+  let name = match args.single_quoted::<String>() {
+    Ok(name) => name,
+    Err(_) => return reply_usage(ctx, msg, "config assignable remove <name>").await
+  };
+
+  apply(ctx, msg, move |config| {
+    match config.assignable.remove(&name) {
+      Some(_) => {
+        config.restricted_assignable.remove(&name);
+        Ok(())
+      },
+      None => Err(Error::Invalid(format!("assignable `{}` does not exist", name)))
+    }
+  }).await
+}
+
+#[command]
+#[sub_commands(greeting_set)]
+async fn greeting(ctx: &Context, msg: &Message) -> CommandResult {
+  react_failure(ctx, msg).await;
+  Ok(())
+}
+
+/// `$config greeting set <text...>`, the text may span several lines
+#[command("set")]
+async fn greeting_set(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
+  let greeting = args.rest().lines().map(str::to_owned).collect::<Vec<String>>();
+  if greeting.is_empty() {
+    return reply_usage(ctx, msg, "config greeting set <text...>").await;
+  };
+
+  apply(ctx, msg, move |config| {
+    config.greeting = greeting;
+    Ok(())
+  }).await
+}
+
+/// Applies an edit to the live config, writes it to disk and replies with what changed
+async fn apply<F>(ctx: &Context, msg: &Message, f: F) -> CommandResult
+where F: FnOnce(&mut Config) -> Result<(), Error> + Send {
+  let config = data_get::<ConfigContainer>(ctx).await;
+  let result = config.update(|current| current.edit(f)).await;
+  let guild_id = config.load().guild;
+
+  match result {
+    Ok(diff) => {
+      let mut report = diff.to_string();
+      for warning in diff.warnings(&member_roles(&ctx.cache, guild_id).await) {
+        report.push_str(&format!("\u{26a0} {}\n", warning));
+      };
+
+      msg.reply(&ctx, truncate(&report, 1900)).await.report_with("Failed to send message");
+      react_success(ctx, msg).await;
+    },
+    Err(err) => {
+      msg.reply(&ctx, format!("Couldn't change the config: {}", err.describe())).await
+        .report_with("Failed to send message");
+      react_failure(ctx, msg).await;
+    }
+  };
+
+  Ok(())
+}
+
+fn rank_index(config: &Config, name: &str) -> Result<usize, Error> {
+  config.ranks.iter()
+    .position(|rank| rank.name == name)
+    .ok_or_else(|| Error::Invalid(format!("rank `{}` does not exist", name)))
+}
@@ -9,11 +9,14 @@ use serenity::{
   }
 };
 
-use crate::util::ResultExt;
+use crate::data::config::ConfigContainer;
+use crate::handler::data_get;
+use crate::util::{ResultExt, truncate};
 use super::*;
 
 #[group]
-#[commands(ping, emoji_data)]
+#[checks(permitted)]
+#[commands(ping, emoji_data, perms)]
 struct General;
 
 #[command]
@@ -24,16 +27,42 @@ async fn ping(ctx: &Context, msg: &Message) -> CommandResult {
 
 #[command("emojidata")]
 async fn emoji_data(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
-  use singlefile::serde_multi::formats::json;
+  // Render the emoji in whatever format the config file is written in
+  let format = data_get::<ConfigContainer>(ctx).await.load().format();
   if let Ok(emoji) = args.single::<ReactionType>() {
-    if let Ok(emoji) = json::to_string(&emoji) {
-      msg.reply(&ctx, format!("`{}`", emoji)).await.report();
+    if let Ok(emoji) = format.to_string(&emoji) {
+      msg.reply(&ctx, format!("```\n{}\n```", emoji.trim_end())).await.report();
     } else {
-      react_failure(&ctx, &msg).await;
+      react_failure(ctx, msg).await;
     };
   } else {
-    react_failure(&ctx, &msg).await;
+    react_failure(ctx, msg).await;
   };
 
   Ok(())
 }
+
+/// `$perms [command]`, shows who may run a command, or every command
+#[command]
+async fn perms(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
+  let config = data_get::<ConfigContainer>(ctx).await.load();
+  let entries = match args.rest().trim() {
+    "" => CommandEntry::all().collect::<Vec<CommandEntry>>(),
+    invocation => match CommandEntry::find_by_invocation(invocation) {
+      Some(entry) => vec![entry],
+      None => {
+        react_failure(ctx, msg).await;
+        return Ok(());
+      }
+    }
+  };
+
+  let mut report = String::new();
+  for entry in entries {
+    let (permission, source) = entry.permission(&config);
+    report.push_str(&format!("`${}`: {} ({})\n", entry.key(), permission, source));
+  };
+
+  msg.reply(&ctx, truncate(&report, 1900)).await.report_with("Failed to send message");
+  Ok(())
+}
@@ -12,43 +12,49 @@ use serenity::{
   }
 };
 
-use crate::data::config::{Config, ConfigContainer};
+use crate::data::{reload_config, reload_persist, member_roles, log_config_diff};
+use crate::data::config::{ConfigContainer, ConfigFile};
 use crate::data::persist::PersistContainer;
 use crate::handler::*;
-use crate::util::ResultExt;
+use crate::outbox::OutboxContainer;
+use crate::shutdown::ShutdownContainer;
+use crate::util::{ResultExt, truncate};
+use crate::writer::{Mutation, PersistWriterContainer};
 use super::*;
 
 #[group]
-#[commands(stop, reload, reset_greets, set_rank, promote, demote)]
+#[checks(permitted)]
+#[commands(stop, reload, reset_greets, backup, outbox, set_rank, promote, demote)]
 struct Owner;
 
 #[command]
-#[owners_only]
 async fn stop(ctx: &Context, msg: &Message) -> CommandResult {
   msg.react(&ctx, '\u{2705}').await.report();
-
-  let shard_manager = data_get::<ShardManagerContainer>(&ctx).await;
-  let mut shard_manager_lock = shard_manager.lock().await;
-  shard_manager_lock.shutdown_all().await;
+  data_get::<ShutdownContainer>(ctx).await.request();
 
   Ok(())
 }
 
 #[command]
-#[owners_only]
 async fn reload(ctx: &Context, msg: &Message) -> CommandResult {
-  let config = data_get::<ConfigContainer>(&ctx).await;
-  let mut config_lock = config.write().await;
-  let persist = data_get::<PersistContainer>(&ctx).await;
-  let mut persist_lock = persist.write().await;
-  match (config_lock.refresh(), persist_lock.refresh()) {
-    (Ok(()), Ok(())) => {
-      react_success(&ctx, &msg).await;
+  match (reload_config(&ctx.data).await, reload_persist(&ctx.data).await) {
+    (Ok(diff), Ok(_)) => {
+      let guild_id = data_get::<ConfigContainer>(ctx).await.load().guild;
+      let warnings = diff.warnings(&member_roles(&ctx.cache, guild_id).await);
+      log_config_diff(&diff, &warnings);
+
+      let mut report = diff.to_string();
+      for warning in warnings.iter() {
+        report.push_str(&format!("\u{26a0} {}\n", warning));
+      };
+
+      msg.reply(&ctx, truncate(&report, 1900)).await.report_with("Failed to send message");
+      react_success(ctx, msg).await;
     },
-    (config_result, persist_result) => {     
+    (config_result, persist_result) => {
       config_result.report_with("Failed to reload config");
       persist_result.report_with("Failed to reload persist");
-      react_failure(&ctx, &msg).await;
+      react_failure(ctx, msg).await;
     }
   };
 
@@ -57,45 +63,151 @@ async fn reload(ctx: &Context, msg: &Message) -> CommandResult {
 
 #[command("resetgreets")]
 #[only_in(guilds)]
-#[owners_only]
 async fn reset_greets(ctx: &Context, msg: &Message) -> CommandResult {
-  let persist = data_get::<PersistContainer>(&ctx).await;
-  let mut persist_lock = persist.write().await;
-  
-  let members = msg.guild_id.unwrap()
-    .members(ctx, None, None).await?;
+  // A single request only returns the first thousand members
+  let members = fetch_members(ctx, msg.guild_id.unwrap()).await?;
   let members = members.into_iter()
     .map(|member| member.user.id)
     .collect::<HashSet<UserId>>();
-  persist_lock.greeted_users = members;
-  match persist_lock.commit() {
-    Ok(()) => react_success(&ctx, &msg).await,
+  let writer = data_get::<PersistWriterContainer>(ctx).await;
+  writer.send(Mutation::SetGreeted(members));
+  match writer.flush().await {
+    Ok(()) => react_success(ctx, msg).await,
     Err(err) => {
-      println!("Unable to commit persistence: {:?}", err);
-      react_failure(&ctx, &msg).await;
+      log!(Error, "Unable to commit persistence: {:?}", err);
+      react_failure(ctx, msg).await;
     }
   };
 
   Ok(())
 }
 
+#[command]
+#[sub_commands(backup_now, backup_list)]
+async fn backup(ctx: &Context, msg: &Message) -> CommandResult {
+  react_failure(ctx, msg).await;
+  Ok(())
+}
+
+/// `$backup now`, writes a backup of the persist state
+#[command("now")]
+async fn backup_now(ctx: &Context, msg: &Message) -> CommandResult {
+  // Make sure changes that are still waiting to be written end up in the backup
+  data_get::<PersistWriterContainer>(ctx).await.flush().await
+    .report_with("Failed to commit persist");
+  let persist = data_get::<PersistContainer>(ctx).await;
+  let result = persist.read().await.backup();
+  match result {
+    Ok(backup_path) => {
+      msg.reply(&ctx, format!("Backed up to `{}`", backup_path.display())).await
+        .report_with("Failed to send message");
+      react_success(ctx, msg).await;
+    },
+    Err(err) => {
+      log!(Error, "Unable to back up persistence: {:?}", err);
+      msg.reply(&ctx, format!("Couldn't back up: {}", err.describe())).await
+        .report_with("Failed to send message");
+      react_failure(ctx, msg).await;
+    }
+  };
+
+  Ok(())
+}
+
+/// `$backup list`, lists persist backups, newest first
+#[command("list")]
+async fn backup_list(ctx: &Context, msg: &Message) -> CommandResult {
+  let persist = data_get::<PersistContainer>(ctx).await;
+  let result = persist.read().await.backups();
+  match result {
+    Ok(backups) if backups.is_empty() => {
+      msg.reply(&ctx, "There are no backups").await.report_with("Failed to send message");
+    },
+    Ok(backups) => {
+      let mut report = String::new();
+      for backup_path in backups.iter() {
+        let size = std::fs::metadata(backup_path).map_or(0, |metadata| metadata.len());
+        report.push_str(&format!("`{}` ({} bytes)\n", backup_path.display(), size));
+      };
+
+      msg.reply(&ctx, truncate(&report, 1900)).await.report_with("Failed to send message");
+    },
+    Err(err) => {
+      log!(Error, "Unable to list backups: {:?}", err);
+      react_failure(ctx, msg).await;
+    }
+  };
+
+  Ok(())
+}
+
+/// `$outbox`, lists Discord operations that failed and are waiting to be retried
+#[command]
+#[sub_commands(outbox_clear, outbox_retry)]
+async fn outbox(ctx: &Context, msg: &Message) -> CommandResult {
+  let items = data_get::<OutboxContainer>(ctx).await.items();
+  if items.is_empty() {
+    msg.reply(&ctx, "The outbox is empty").await.report_with("Failed to send message");
+    return Ok(());
+  };
+
+  let mut report = String::new();
+  for item in items.iter() {
+    let status = match item.stuck {
+      true => "stuck".to_owned(),
+      false => format!("retrying, {} attempts", item.attempts)
+    };
+
+    report.push_str(&format!("`{}` {} ({})", item.id, item.operation, status));
+    if let Some(last_error) = &item.last_error {
+      report.push_str(&format!(": {}", last_error));
+    };
+
+    report.push('\n');
+  };
+
+  msg.reply(&ctx, truncate(&report, 1900)).await.report_with("Failed to send message");
+  Ok(())
+}
+
+/// `$outbox clear [id]`, drops an operation, or every stuck operation
+#[command("clear")]
+async fn outbox_clear(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+  let id = args.single::<u64>().ok();
+  let removed = data_get::<OutboxContainer>(ctx).await.clear(id);
+  match removed {
+    0 => react_failure(ctx, msg).await,
+    _ => react_success(ctx, msg).await
+  };
+
+  Ok(())
+}
+
+/// `$outbox retry [id]`, retries an operation, or every stuck operation, right away
+#[command("retry")]
+async fn outbox_retry(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+  let id = args.single::<u64>().ok();
+  let count = data_get::<OutboxContainer>(ctx).await.retry(id);
+  match count {
+    0 => react_failure(ctx, msg).await,
+    _ => react_success(ctx, msg).await
+  };
+
+  Ok(())
+}
+
 #[command("setrank")]
 #[only_in(guilds)]
-#[owners_only]
 async fn set_rank(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
-  let config = data_get::<ConfigContainer>(&ctx).await;
-  let config_lock = config.read().await;
-  if let Some(member) = get_member_from_args(&ctx, &msg, &mut args).await {
-    if let Some(roles) = change_rank(&config_lock, &member, Scheme::Named(args.rest())) {
-      match member.edit(&ctx, |edit| edit.roles(roles)).await {
-        Ok(_) => react_success(&ctx, &msg).await,
-        Err(_) => react_failure(&ctx, &msg).await
-      };
-    } else {
-      react_failure(&ctx, &msg).await;
-    };
-  } else {
-    react_failure(&ctx, &msg).await;
+  let config = data_get::<ConfigContainer>(ctx).await.load();
+  match get_members_from_args(ctx, msg, &config, &mut args, true).await {
+    Ok(members) => {
+      let authority = Authority::of(ctx, msg, &config).await;
+      let key = format!("setrank:{}", args.rest().to_lowercase());
+      let scheme = Scheme::Named(args.rest());
+      change_roles(ctx, msg, "Setting the rank of", members, Some(&key), |member| change_rank(&config, &authority, member, scheme)).await;
+    },
+    Err(reason) => reply_failure(ctx, msg, &reason).await
   };
 
   Ok(())
@@ -103,21 +215,14 @@ async fn set_rank(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult
 
 #[command]
 #[only_in(guilds)]
-#[owners_only]
 async fn promote(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
-  let config = data_get::<ConfigContainer>(&ctx).await;
-  let config_lock = config.read().await;
-  if let Some(member) = get_member_from_args(&ctx, &msg, &mut args).await {
-    if let Some(roles) = change_rank(&config_lock, &member, Scheme::Higher) {
-      match member.edit(&ctx, |edit| edit.roles(roles)).await {
-        Ok(_) => react_success(&ctx, &msg).await,
-        Err(_) => react_failure(&ctx, &msg).await
-      };
-    } else {
-      react_failure(&ctx, &msg).await;
-    };
-  } else {
-    react_failure(&ctx, &msg).await;
+  let config = data_get::<ConfigContainer>(ctx).await.load();
+  match get_members_from_args(ctx, msg, &config, &mut args, false).await {
+    Ok(members) => {
+      let authority = Authority::of(ctx, msg, &config).await;
+      change_roles(ctx, msg, "Promoting", members, None, |member| change_rank(&config, &authority, member, Scheme::Higher)).await;
+    },
+    Err(reason) => reply_failure(ctx, msg, &reason).await
   };
 
   Ok(())
@@ -125,45 +230,57 @@ async fn promote(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult
 
 #[command]
 #[only_in(guilds)]
-#[owners_only]
 async fn demote(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
-  let config = data_get::<ConfigContainer>(&ctx).await;
-  let config_lock = config.read().await;
-  if let Some(member) = get_member_from_args(&ctx, &msg, &mut args).await {
-    if let Some(roles) = change_rank(&config_lock, &member, Scheme::Lower) {
-      match member.edit(&ctx, |edit| edit.roles(roles)).await {
-        Ok(_) => react_success(&ctx, &msg).await,
-        Err(_) => react_failure(&ctx, &msg).await
-      };
-    } else {
-      react_failure(&ctx, &msg).await;
-    };
-  } else {
-    react_failure(&ctx, &msg).await;
+  let config = data_get::<ConfigContainer>(ctx).await.load();
+  match get_members_from_args(ctx, msg, &config, &mut args, false).await {
+    Ok(members) => {
+      let authority = Authority::of(ctx, msg, &config).await;
+      change_roles(ctx, msg, "Demoting", members, None, |member| change_rank(&config, &authority, member, Scheme::Lower)).await;
+    },
+    Err(reason) => reply_failure(ctx, msg, &reason).await
   };
 
   Ok(())
 }
 
-fn change_rank(config: &Config, member: &Member, scheme: Scheme<'_>) -> Option<HashSet<RoleId>> {
-  let ranks = config.get_member_ranks(&member.roles);
-  let old_rank = *ranks.first()?;
+pub fn change_rank(config: &ConfigFile, authority: &Authority, member: &Member, scheme: Scheme<'_>) -> RoleChange {
+  let (old_index, old_rank) = match member.roles.iter().find_map(|&role| config.get_rank_by_role(role)) {
+    Some(old) => old,
+    None => return RoleChange::Skip
+  };
+
   let new_rank = match scheme {
-    Scheme::Lower => config.get_lower_rank(&old_rank.name)?,
-    Scheme::Higher => config.get_higher_rank(&old_rank.name)?,
-    Scheme::Named(name) => config.get_rank_by_name_loose(name)?
+    Scheme::Lower => config.get_lower_rank(&old_rank.name),
+    Scheme::Higher => config.get_higher_rank(&old_rank.name),
+    Scheme::Named(name) => config.get_rank_by_name_loose(name)
+  };
+
+  let (new_index, new_rank) = match new_rank.and_then(|new_rank| config.get_rank_by_role(new_rank.role)) {
+    Some(new) => new,
+    None => return RoleChange::Skip
   };
 
-  if old_rank == new_rank { return None };
+  if old_rank == new_rank { return RoleChange::Skip };
+
+  if config.requires_review(&new_rank.name) && !matches!(authority, Authority::Owner) {
+    return RoleChange::Deny(format!("{} can only be given through `$recommend`", new_rank.name));
+  };
+
+  // Both the member's current rank and the one they're getting have to be below the actor's
+  let action = format!("Changing {}'s rank to {}", member.display_name(), new_rank.name);
+  if let Err(reason) = authority.check_above(config, old_index.max(new_index), &action) {
+    return RoleChange::Deny(reason);
+  };
 
   let mut roles: HashSet<RoleId> = member.roles.iter().cloned().collect();
   roles.insert(new_rank.role);
   roles.remove(&old_rank.role);
 
-  Some(roles)
+  RoleChange::Set(roles)
 }
 
-enum Scheme<'a> {
+#[derive(Clone, Copy)]
+pub enum Scheme<'a> {
   Lower,
   Higher,
   Named(&'a str)
@@ -0,0 +1,282 @@
+use std::time::Duration;
+
+use serenity::{
+  prelude::*,
+  model::{
+    id::{GuildId, UserId},
+    channel::Message,
+    guild::Member
+  }
+};
+
+/// How long to wait for an answer when a name matches several members, or to a confirmation
+const PROMPT_TIMEOUT: Duration = Duration::from_secs(30);
+/// How many candidates to offer when a name matches several members
+const MAX_CANDIDATES: usize = 10;
+/// The most edits a name may be away from a member's name and still match, see `max_distance`
+const MAX_DISTANCE: usize = 2;
+
+/// Whether an argument names exactly one user, as a mention, id or `name#discriminator`
+pub fn is_exact(query: &str) -> bool {
+  query.parse::<UserId>().is_ok() || parse_tag(query).is_some()
+}
+
+/// Finds the member an argument refers to: a mention, an id, `name#discriminator`, or a
+/// username or nickname, matched loosely. Members not in the cache are fetched from Discord.
+/// Only an exact name or tag is taken as is; if a name matches several members, or only loosely,
+/// the author is asked which one they meant.
+pub async fn resolve_member(ctx: &Context, msg: &Message, guild_id: GuildId, query: &str) -> Result<Member, String> {
+  let query = query.trim();
+  if let Ok(user_id) = query.parse::<UserId>() {
+    if let Some(member) = ctx.cache.member(guild_id, user_id).await {
+      return Ok(member);
+    };
+
+    return ctx.http.get_member(guild_id.0, user_id.0).await
+      .map_err(|_| format!("Couldn't find member {}", user_id));
+  };
+
+  let mut cached = ctx.cache.guild_field(guild_id, |guild| guild.members.values().cloned().collect::<Vec<Member>>()).await
+    .unwrap_or_default();
+  let (exact, mut candidates) = match best_matches(&cached, query) {
+    (true, candidates) if !candidates.is_empty() => (true, candidates),
+    // A loose match in the cache could hide an exact match on a member the bot hasn't seen,
+    // so search Discord as well and match against both
+    (_, candidates) => {
+      // Discord searches usernames and nicknames by prefix, so search for the name without a tag
+      let name = parse_tag(query).map_or(query, |(name, _)| name);
+      match guild_id.search_members(&ctx.http, name, Some(MAX_CANDIDATES as u64)).await {
+        Ok(found) => {
+          cached.retain(|member| !found.iter().any(|other| other.user.id == member.user.id));
+          cached.extend(found);
+          best_matches(&cached, query)
+        },
+        Err(err) if !candidates.is_empty() => {
+          log!(Warn, "Couldn't search for members, going by the cache: {:?}", err);
+          (false, candidates)
+        },
+        Err(err) => return Err(format!("Couldn't search for members: {}", err))
+      }
+    }
+  };
+
+  match (candidates.len(), exact) {
+    (0, _) => Err(format!("No member matches `{}`", query)),
+    (1, true) => Ok(candidates.remove(0)),
+    _ => disambiguate(ctx, msg, query, candidates).await
+  }
+}
+
+/// Splits `name#1234` into its name and discriminator
+fn parse_tag(query: &str) -> Option<(&str, u16)> {
+  let (name, discriminator) = query.rsplit_once('#')?;
+  match (name.is_empty(), discriminator.len(), discriminator.parse::<u16>()) {
+    (false, 4, Ok(discriminator)) => Some((name, discriminator)),
+    _ => None
+  }
+}
+
+/// The members that match a query best, from exact matches down to names a few typos away,
+/// along with whether they're exact matches (a whole name or a tag).
+/// Only members from the best kind of match that any member reaches are returned.
+fn best_matches(members: &[Member], query: &str) -> (bool, Vec<Member>) {
+  if let Some((name, discriminator)) = parse_tag(query) {
+    let found = members.iter()
+      .filter(|member| member.user.discriminator == discriminator && member.user.name.eq_ignore_ascii_case(name))
+      .cloned()
+      .collect();
+    return (true, found);
+  };
+
+  let query = query.to_lowercase();
+  let max_distance = max_distance(&query);
+  let score = |member: &Member| -> Option<usize> {
+    std::iter::once(&member.user.name).chain(member.nick.iter())
+      .filter_map(|name| {
+        let name = name.to_lowercase();
+        if name == query {
+          Some(0)
+        } else if name.starts_with(&query) {
+          Some(1)
+        } else if name.contains(&query) {
+          Some(2)
+        } else {
+          match distance(&name, &query) {
+            distance if distance <= max_distance => Some(3),
+            _ => None
+          }
+        }
+      })
+      .min()
+  };
+
+  let scored = members.iter()
+    .filter_map(|member| score(member).map(|score| (score, member)))
+    .collect::<Vec<(usize, &Member)>>();
+  let best = match scored.iter().map(|&(score, _)| score).min() {
+    Some(best) => best,
+    None => return (false, Vec::new())
+  };
+
+  let found = scored.into_iter()
+    .filter(|&(score, _)| score == best)
+    .map(|(_, member)| member.clone())
+    .collect();
+  (best == 0, found)
+}
+
+/// How many edits a query may be away from a name, so that short queries don't match every
+/// short name: none below 4 characters, then one more for every 4 characters up to `MAX_DISTANCE`
+fn max_distance(query: &str) -> usize {
+  (query.chars().count() / 4).min(MAX_DISTANCE)
+}
+
+/// The number of single character edits it takes to turn one string into another
+fn distance(a: &str, b: &str) -> usize {
+  let b = b.chars().collect::<Vec<char>>();
+  let mut previous = (0..=b.len()).collect::<Vec<usize>>();
+  for (i, a) in a.chars().enumerate() {
+    let mut current = vec![i + 1; b.len() + 1];
+    for (j, &b) in b.iter().enumerate() {
+      let substitution = previous[j] + if a == b { 0 } else { 1 };
+      current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+    };
+
+    previous = current;
+  };
+
+  previous[b.len()]
+}
+
+/// Lists the candidates and waits for the author to reply with the number of the one they meant.
+/// A single loose match is confirmed the same way.
+async fn disambiguate(ctx: &Context, msg: &Message, query: &str, mut candidates: Vec<Member>) -> Result<Member, String> {
+  candidates.sort_by_key(|member| member.display_name().to_lowercase());
+  candidates.truncate(MAX_CANDIDATES);
+  let mut prompt = match candidates.len() {
+    1 => format!("Nobody is called exactly `{}`, reply with 1 if you meant:\n", query),
+    _ => format!("`{}` could be any of these, reply with a number to pick one:\n", query)
+  };
+  for (i, member) in candidates.iter().enumerate() {
+    prompt.push_str(&format!("{}. {} ({})\n", i + 1, member.display_name(), member.user.tag()));
+  };
+
+  msg.reply(&ctx, prompt).await
+    .map_err(|err| format!("Couldn't ask which member was meant: {}", err))?;
+  let answer = msg.author.await_reply(&ctx)
+    .channel_id(msg.channel_id)
+    .timeout(PROMPT_TIMEOUT)
+    .await
+    .ok_or_else(|| format!("No member was picked for `{}`", query))?;
+
+  match answer.content.trim().parse::<usize>() {
+    Ok(choice) if (1..=candidates.len()).contains(&choice) => Ok(candidates.swap_remove(choice - 1)),
+    _ => Err(format!("`{}` isn't one of the choices", answer.content.trim()))
+  }
+}
+
+/// Asks the author a yes or no question and waits for their reply. Anything but `yes`, or no
+/// reply in time, counts as no.
+pub async fn confirm(ctx: &Context, msg: &Message, question: &str) -> Result<bool, String> {
+  msg.reply(&ctx, question).await
+    .map_err(|err| format!("Couldn't ask for confirmation: {}", err))?;
+  let answer = msg.author.await_reply(&ctx)
+    .channel_id(msg.channel_id)
+    .timeout(PROMPT_TIMEOUT)
+    .await;
+
+  Ok(answer.is_some_and(|answer| answer.content.trim().eq_ignore_ascii_case("yes")))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn member(id: u64, name: &str, discriminator: u16, nick: Option<&str>) -> Member {
+    serde_json::from_value(serde_json::json!({
+      "deaf": false,
+      "mute": false,
+      "guild_id": "1",
+      "joined_at": null,
+      "premium_since": null,
+      "pending": false,
+      "nick": nick,
+      "roles": [],
+      "user": {
+        "id": id.to_string(),
+        "username": name,
+        "discriminator": format!("{:04}", discriminator),
+        "avatar": null,
+        "bot": false
+      }
+    })).unwrap()
+  }
+
+  fn ids(members: &[Member]) -> Vec<u64> {
+    let mut ids = members.iter().map(|member| member.user.id.0).collect::<Vec<u64>>();
+    ids.sort_unstable();
+    ids
+  }
+
+  #[test]
+  fn distance_counts_edits() {
+    assert_eq!(distance("", ""), 0);
+    assert_eq!(distance("scotty", "scotty"), 0);
+    assert_eq!(distance("scotty", "scoty"), 1);
+    assert_eq!(distance("scotty", "scptty"), 1);
+    assert_eq!(distance("kitten", "sitting"), 3);
+    assert_eq!(distance("", "abc"), 3);
+  }
+
+  #[test]
+  fn tags_need_a_name_and_four_digits() {
+    assert_eq!(parse_tag("Scotty#1234"), Some(("Scotty", 1234)));
+    assert_eq!(parse_tag("a#b#0001"), Some(("a#b", 1)));
+    assert_eq!(parse_tag("#1234"), None);
+    assert_eq!(parse_tag("Scotty#123"), None);
+    assert_eq!(parse_tag("Scotty#abcd"), None);
+    assert_eq!(parse_tag("Scotty"), None);
+  }
+
+  #[test]
+  fn best_matches_prefers_closer_kinds_of_match() {
+    let members = vec![
+      member(1, "Scotty", 1, None),
+      member(2, "ScottyThePilot", 2, None),
+      member(3, "Bob", 3, Some("The Pilot")),
+      member(4, "Scotty", 4, None)
+    ];
+
+    let (exact, found) = best_matches(&members, "scotty");
+    assert!(exact);
+    assert_eq!(ids(&found), vec![1, 4]);
+
+    let (exact, found) = best_matches(&members, "Scotty#0004");
+    assert!(exact);
+    assert_eq!(ids(&found), vec![4]);
+
+    let (exact, found) = best_matches(&members, "scottythe");
+    assert!(!exact);
+    assert_eq!(ids(&found), vec![2]);
+
+    // Nicknames count too
+    let (exact, found) = best_matches(&members, "pilot");
+    assert!(!exact);
+    assert_eq!(ids(&found), vec![2, 3]);
+
+    let (exact, found) = best_matches(&members, "the pilot");
+    assert!(exact);
+    assert_eq!(ids(&found), vec![3]);
+  }
+
+  #[test]
+  fn typos_allowed_grow_with_the_query() {
+    let members = vec![member(1, "Bob", 1, None), member(2, "Scotty", 2, None)];
+    assert!(best_matches(&members, "bib").1.is_empty());
+    assert_eq!(ids(&best_matches(&members, "scoty").1), vec![2]);
+    assert!(best_matches(&members, "sxxtty").1.is_empty());
+    assert_eq!(max_distance("abc"), 0);
+    assert_eq!(max_distance("abcd"), 1);
+    assert_eq!(max_distance("abcdefghijkl"), MAX_DISTANCE);
+  }
+}
@@ -1,2 +1,108 @@
+pub mod backup;
 pub mod config;
+pub mod diff;
+pub mod format;
+pub mod merge;
+pub mod migrate;
+pub mod outbox;
 pub mod persist;
+pub mod recommend;
+pub mod secrets;
+pub mod sqlite;
+pub mod store;
+#[cfg(test)]
+pub mod fixtures;
+
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+
+use serenity::{
+  prelude::*,
+  cache::Cache,
+  model::id::{GuildId, RoleId}
+};
+
+use crate::commands::unknown_permission_keys;
+use crate::error::Error;
+use crate::handler::data_get_from;
+use crate::outbox::OutboxContainer;
+use crate::recommend::RecommendationsContainer;
+use self::config::ConfigContainer;
+use self::diff::ConfigDiff;
+use self::persist::PersistContainer;
+
+/// Re-reads the config file, keeping the current config if the new one fails to load or validate.
+/// Returns what changed between the old and new configs, along with unknown permission entries.
+pub async fn reload_config(data: &RwLock<TypeMap>) -> Result<ConfigDiff, Error> {
+  let config = data_get_from::<ConfigContainer>(data).await;
+  config.update(|current| {
+    let new_config = current.reopen()?;
+    new_config.validate()?;
+    let mut diff = ConfigDiff::new(current, &new_config);
+    diff.unknown_permissions = unknown_permission_keys(&new_config).into_iter().map(str::to_owned).collect();
+    Ok((new_config, diff))
+  }).await
+}
+
+/// Picks up outside changes to the persist store, returning whether anything changed.
+/// The outbox and recommendations keep their own copies of their items, so they're reloaded
+/// from the store as well.
+pub async fn reload_persist(data: &RwLock<TypeMap>) -> Result<bool, Error> {
+  let persist = data_get_from::<PersistContainer>(data).await;
+  let mut persist_lock = persist.write().await;
+  let changed = persist_lock.refresh()?;
+  if changed {
+    data_get_from::<OutboxContainer>(data).await.reload(persist_lock.outbox()?);
+    data_get_from::<RecommendationsContainer>(data).await.reload(persist_lock.recommendations()?);
+  };
+
+  Ok(changed)
+}
+
+/// Collects the roles of every cached member of a guild
+pub async fn member_roles(cache: &Cache, guild_id: GuildId) -> Vec<Vec<RoleId>> {
+  cache.guild(guild_id).await
+    .map(|guild| guild.members.values().map(|member| member.roles.clone()).collect())
+    .unwrap_or_default()
+}
+
+pub fn log_config_diff(diff: &ConfigDiff, warnings: &[String]) {
+  log!(Info, "Config reloaded:\n{}", diff.to_string().trim_end());
+  for warning in warnings {
+    log!(Warn, "Warning: {}", warning);
+  };
+}
+
+/// Writes a file atomically (see `replace_atomic`), keeping the previous version of the file,
+/// if any, as `<file>.bak`.
+pub fn write_atomic(path: &Path, contents: &str) -> Result<(), Error> {
+  if path.exists() {
+    let file_name = path.file_name().map_or("file".into(), |name| name.to_string_lossy());
+    fs::copy(path, path.with_file_name(format!("{}.bak", file_name)))?;
+  };
+
+  replace_atomic(path, contents)
+}
+
+/// Writes a file by writing to a temporary file next to it, syncing that to disk and
+/// renaming it into place, so the file is never left half-written even if the bot crashes.
+pub fn replace_atomic(path: &Path, contents: &str) -> Result<(), Error> {
+  let file_name = path.file_name().map_or("file".into(), |name| name.to_string_lossy());
+  let tmp_path = path.with_file_name(format!("{}.tmp", file_name));
+
+  let mut file = File::create(&tmp_path)?;
+  file.write_all(contents.as_bytes())?;
+  file.sync_all()?;
+
+  if let Ok(metadata) = fs::metadata(path) {
+    fs::set_permissions(&tmp_path, metadata.permissions())?;
+  };
+
+  fs::rename(&tmp_path, path)?;
+
+  // Sync the directory too, otherwise the rename itself may not survive a crash
+  let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or_else(|| Path::new("."));
+  File::open(dir)?.sync_all()?;
+  Ok(())
+}
@@ -0,0 +1,94 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::error::Error;
+
+/// The directory backups are kept in, next to the file being backed up
+pub const BACKUP_DIR: &str = "backups";
+
+/// How long automatic backups wait after the newest backup before taking another, so that
+/// the backups kept reach back further than the last few writes
+pub const BACKUP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Finds the backup directory for a file
+pub fn backup_dir(path: &Path) -> PathBuf {
+  path.parent().unwrap_or_else(|| Path::new("")).join(BACKUP_DIR)
+}
+
+/// Picks a new timestamped backup path for a file, e.g. `backups/persist-1617000000000.json`
+pub fn new_backup_path(path: &Path) -> Result<PathBuf, Error> {
+  let (stem, extension) = split_name(path);
+  let dir = backup_dir(path);
+  fs::create_dir_all(&dir)?;
+
+  Ok(dir.join(format!("{}-{}{}", stem, now_millis(), extension)))
+}
+
+/// Whether the newest backup of a file is older than `BACKUP_INTERVAL`, or there is none
+pub fn is_due(path: &Path) -> Result<bool, Error> {
+  Ok(match timestamped(path)?.first() {
+    Some(&(newest, _)) => now_millis().saturating_sub(newest) >= BACKUP_INTERVAL.as_millis(),
+    None => true
+  })
+}
+
+/// Copies a file into its backup directory, then removes all but the `limit` newest backups
+pub fn create(path: &Path, limit: usize) -> Result<PathBuf, Error> {
+  let backup_path = new_backup_path(path)?;
+  fs::copy(path, &backup_path)?;
+  prune(path, limit)?;
+  Ok(backup_path)
+}
+
+/// Lists the backups of a file, newest first
+pub fn list(path: &Path) -> Result<Vec<PathBuf>, Error> {
+  Ok(timestamped(path)?.into_iter().map(|(_, path)| path).collect())
+}
+
+/// Lists the backups of a file along with when they were taken, newest first
+fn timestamped(path: &Path) -> Result<Vec<(u128, PathBuf)>, Error> {
+  let (stem, extension) = split_name(path);
+  let prefix = format!("{}-", stem);
+  let entries = match fs::read_dir(backup_dir(path)) {
+    Ok(entries) => entries,
+    Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+    Err(err) => return Err(err.into())
+  };
+
+  let mut backups = Vec::new();
+  for entry in entries {
+    let entry = entry?;
+    let file_name = entry.file_name().to_string_lossy().into_owned();
+    let timestamp = file_name.strip_prefix(&prefix)
+      .and_then(|rest| rest.strip_suffix(&extension))
+      .and_then(|timestamp| timestamp.parse::<u128>().ok());
+    if let Some(timestamp) = timestamp {
+      backups.push((timestamp, entry.path()));
+    };
+  };
+
+  backups.sort_by_key(|&(timestamp, _)| std::cmp::Reverse(timestamp));
+  Ok(backups)
+}
+
+/// Removes all but the `limit` newest backups of a file
+pub fn prune(path: &Path, limit: usize) -> Result<(), Error> {
+  for backup_path in list(path)?.into_iter().skip(limit) {
+    fs::remove_file(backup_path)?;
+  };
+
+  Ok(())
+}
+
+fn now_millis() -> u128 {
+  SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_millis())
+}
+
+/// Splits a file name into its stem and its extension (including the dot, if any)
+fn split_name(path: &Path) -> (String, String) {
+  let stem = path.file_stem().map_or("backup".into(), |stem| stem.to_string_lossy().into_owned());
+  let extension = path.extension().map_or(String::new(), |extension| format!(".{}", extension.to_string_lossy()));
+  (stem, extension)
+}
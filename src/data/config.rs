@@ -1,10 +1,15 @@
-use std::collections::{HashSet, BTreeMap};
+use std::collections::{HashMap, HashSet, BTreeMap};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
-use singlefile::serde_multi::formats::json::Json;
-use singlefile::BackendReadonly;
+use arc_swap::ArcSwap;
 use serenity::{
-  prelude::{TypeMapKey, RwLock},
+  prelude::{TypeMapKey, Mutex},
   model::{
     channel::{Reaction, ReactionType},
     id::{
@@ -14,22 +19,462 @@ use serenity::{
   }
 };
 
-pub type ConfigFile = BackendReadonly<Config, Json>;
+use crate::error::Error;
+use super::diff::ConfigDiff;
+use super::format::Format;
+use super::merge::{self, merge};
+use super::migrate::{self, Kind};
+use super::secrets::{self, Secrets, TOKEN_VAR};
+
+/// The config file used when none is given, `config.ron` or `config.toml` are used instead if present
+pub const CONFIG_PATH: &str = "config.json";
+
+/// Fields that are only read at startup, so changes to them require a restart
+pub const RESTART_FIELDS: &[&str] = &["owners", "token", "token_file", "http_address", "systemd_notify", "persist_backups"];
+
+/// A config loaded from disk, with environment variables interpolated and secrets resolved.
+///
+/// A config consists of a base file (e.g. `config.json`) which may be shared between deployments,
+/// and an optional override file next to it (e.g. `config.local.json`) which is merged on top.
+///
+/// A `ConfigFile` is never changed once loaded, edits and reloads produce a new one instead.
+#[derive(Debug)]
+pub struct ConfigFile {
+  config: Config,
+  index: ConfigIndex,
+  path: PathBuf,
+  format: Format,
+  /// The layers that had a token written directly in them
+  token_layers: Vec<PathBuf>
+}
+
+impl ConfigFile {
+  /// Opens a config file, in the given format or else the format matching its extension
+  pub fn open(path: impl AsRef<Path>, format: Option<Format>) -> Result<ConfigFile, Error> {
+    let path = path.as_ref().to_owned();
+    let format = format_for(&path, format);
+    let (mut value, token_layers) = load_layers(&path, format, true)?;
+    secrets::interpolate(&mut value)?;
+    let mut config: Config = serde_json::from_value(value)?;
+    config.token = Some(config.resolve_token()?);
+    let index = ConfigIndex::new(&config);
+    Ok(ConfigFile { config, index, path, format, token_layers })
+  }
+
+  /// Reopens this config file from disk
+  pub fn reopen(&self) -> Result<ConfigFile, Error> {
+    ConfigFile::open(&self.path, Some(self.format))
+  }
+
+  /// The path of the base config file
+  pub fn path(&self) -> &Path {
+    &self.path
+  }
+
+  pub fn format(&self) -> Format {
+    self.format
+  }
+
+  /// Applies and validates a change to the config, then writes the changed fields back to disk,
+  /// returning the edited config.
+  ///
+  /// Changes are written to the raw layers, keeping any `${VAR}` references whose value didn't
+  /// change. Without an override file everything goes to the base, otherwise changed fields are
+  /// split between the layers as described in `merge::unmerge`: the base, which may be shared,
+  /// only sees changes to what it already defines, and new fields and entries go to the override.
+  /// Fails if an entry only the override defines is moved before the base's. The token is never
+  /// written back.
+  pub fn edit<F>(&self, f: F) -> Result<(ConfigFile, ConfigDiff), Error>
+  where F: FnOnce(&mut Config) -> Result<(), Error> {
+    let mut config = self.config.clone();
+    f(&mut config)?;
+    config.validate()?;
+
+    let new_value = serde_json::to_value(&config)?;
+    let override_path = override_path(&self.path);
+    let old_base = self.format.parse(&fs::read_to_string(&self.path)?)?;
+    let old_over = match fs::read_to_string(&override_path) {
+      Ok(contents) => Some(self.format.parse(&contents)?),
+      Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+      Err(err) => return Err(err.into())
+    };
+
+    let mut raw = old_base.clone();
+    if let Some(old_over) = &old_over {
+      merge(&mut raw, old_over.clone());
+    };
+
+    let (mut base, mut over) = (old_base.clone(), old_over.clone());
+    let base_map = base.as_object_mut()
+      .ok_or(Error::Custom("expected a map at the top level of the config file"))?;
+    for field in self.config.changed_fields(&config) {
+      if field == "token" { continue };
+      let mut value = new_value[field].clone();
+      if let Some(raw) = raw.get(field) {
+        secrets::restore_references(&mut value, raw);
+      };
+
+      match over.as_mut().and_then(serde_json::Value::as_object_mut) {
+        Some(over) => merge::unmerge_key(base_map, over, field, value)?,
+        None => { base_map.insert(field.to_owned(), value); }
+      };
+    };
+
+    if base != old_base {
+      super::write_atomic(&self.path, &self.format.render(&base)?)?;
+    };
+
+    if let Some(over) = over.filter(|over| Some(over) != old_over.as_ref()) {
+      super::write_atomic(&override_path, &self.format.render(&over)?)?;
+    };
+
+    let diff = ConfigDiff::new(&self.config, &config);
+    let index = ConfigIndex::new(&config);
+    let path = self.path.clone();
+    let token_layers = self.token_layers.clone();
+    Ok((ConfigFile { config, index, path, format: self.format, token_layers }, diff))
+  }
+
+  /// The paths of every layer this config may be loaded from, whether or not they exist
+  pub fn paths(&self) -> Vec<PathBuf> {
+    vec![self.path.clone(), override_path(&self.path)]
+  }
+
+  /// Fails if any config layer contains a token and can be read by any user
+  pub fn check_permissions(&self) -> Result<(), Error> {
+    for path in self.token_layers.iter() {
+      if secrets::is_world_readable(path)? {
+        let message = format!(
+          "{} contains a token and is world-readable; move the token to {} or ${}, or `chmod o-r` the file",
+          path.display(), secrets::SECRETS_PATH, TOKEN_VAR
+        );
+
+        return Err(Error::Invalid(message));
+      };
+    };
+
+    Ok(())
+  }
+}
+
+/// Lookups backed by the index
+impl ConfigFile {
+  pub fn is_admin_role(&self, role_id: RoleId) -> bool {
+    self.index.admin_roles.contains(&role_id)
+  }
+
+  pub fn is_role_menu_reaction(&self, react: &Reaction) -> bool {
+    self.role_menu == (react.channel_id, react.message_id) &&
+    self.index.menu_emojis.contains_key(&react.emoji)
+  }
+
+  pub fn should_grant_position(&self, position: Option<&Position>) -> bool {
+    position.is_none_or(|position| self.index.menu_names.contains_key(&position.name))
+  }
+
+  pub fn get_role_menu_position(&self, emoji: &ReactionType) -> Option<&RoleMenuPosition> {
+    self.index.menu_emojis.get(emoji).map(|&index| &self.role_menu_positions[index])
+  }
+
+  pub fn get_role_menu_emoji(&self, position_name: &str) -> Option<ReactionType> {
+    self.index.menu_names.get(position_name).map(|&index| self.role_menu_positions[index].emoji.clone())
+  }
+
+  pub fn get_rank_by_name(&self, rank_name: &str) -> Option<&Rank> {
+    self.index.rank_names.get(rank_name).map(|&index| &self.ranks[index])
+  }
+
+  pub fn get_rank_by_name_loose(&self, rank_name: &str) -> Option<&Rank> {
+    self.index.rank_names_lower.get(&rank_name.to_lowercase()).map(|&index| &self.ranks[index])
+  }
+
+  /// Finds the rank a role belongs to, along with its place on the ladder (0 is the lowest)
+  pub fn get_rank_by_role(&self, role_id: RoleId) -> Option<(usize, &Rank)> {
+    self.index.rank_roles.get(&role_id).map(|&index| (index, &self.ranks[index]))
+  }
+
+  pub fn get_member_ranks(&self, roles: &[RoleId]) -> Vec<&Rank> {
+    roles.iter()
+      .filter_map(|&role| self.get_rank_by_role(role))
+      .map(|(_, rank)| rank)
+      .collect()
+  }
+
+  pub fn get_higher_rank(&self, rank_name: &str) -> Option<&Rank> {
+    let index = *self.index.rank_names.get(rank_name)?;
+    self.ranks.get(index + 1)
+  }
+
+  pub fn get_lower_rank(&self, rank_name: &str) -> Option<&Rank> {
+    let index = *self.index.rank_names.get(rank_name)?;
+    self.ranks.get(index.checked_sub(1)?)
+  }
+
+  pub fn get_position_by_name(&self, position_name: &str) -> Option<&Position> {
+    self.index.position_names.get(position_name).map(|&index| &self.positions[index])
+  }
+
+  pub fn get_position_by_role(&self, role_id: RoleId) -> Option<&Position> {
+    self.index.position_roles.get(&role_id).map(|&index| &self.positions[index])
+  }
+
+  pub fn get_member_positions(&self, roles: &[RoleId]) -> Vec<&Position> {
+    roles.iter()
+      .filter_map(|&role| self.get_position_by_role(role))
+      .collect()
+  }
+
+  pub fn get_assignable_loose(&self, assignable_name: &str) -> Option<RoleId> {
+    self.index.assignables_lower.get(&assignable_name.to_lowercase()).copied()
+  }
+
+  /// The rank that only members above may assign or unassign this role, if it is restricted
+  pub fn get_assignable_restriction(&self, role_id: RoleId) -> Option<(usize, &Rank)> {
+    self.index.restricted_roles.get(&role_id).map(|&index| (index, &self.ranks[index]))
+  }
+
+  /// The highest rank a member holds, along with its place on the ladder
+  pub fn get_highest_rank(&self, roles: &[RoleId]) -> Option<(usize, &Rank)> {
+    roles.iter()
+      .filter_map(|&role| self.get_rank_by_role(role))
+      .max_by_key(|&(index, _)| index)
+  }
+
+  /// Whether a rank can only be given through an approved recommendation
+  pub fn requires_review(&self, rank_name: &str) -> bool {
+    self.review.as_ref().is_some_and(|review| review.ranks.contains(rank_name))
+  }
+
+  /// Whether a member ranks high enough for their votes on recommendations to count
+  pub fn is_approver(&self, roles: &[RoleId]) -> bool {
+    let min_index = match self.review.as_ref().and_then(|review| self.index.rank_names.get(&review.approver_rank)) {
+      Some(&min_index) => min_index,
+      None => return false
+    };
+
+    self.get_highest_rank(roles).is_some_and(|(index, _)| index >= min_index)
+  }
+
+  /// Whether a member matches a permission, not counting owners, users or the Administrator permission
+  pub fn member_matches(&self, permission: &Permission, roles: &[RoleId]) -> bool {
+    if permission.admins && roles.iter().any(|&role| self.is_admin_role(role)) {
+      return true;
+    };
+
+    if roles.iter().any(|role| permission.roles.contains(role)) {
+      return true;
+    };
+
+    if self.get_member_positions(roles).iter().any(|position| permission.positions.contains(&position.name)) {
+      return true;
+    };
+
+    match permission.min_rank.as_ref().and_then(|min_rank| self.index.rank_names.get(min_rank)) {
+      Some(&min_index) => roles.iter()
+        .filter_map(|&role| self.get_rank_by_role(role))
+        .any(|(index, _)| index >= min_index),
+      None => false
+    }
+  }
+}
+
+impl Deref for ConfigFile {
+  type Target = Config;
+
+  fn deref(&self) -> &Config {
+    &self.config
+  }
+}
+
+/// The path of the override file for a base config file, `config.json` becomes `config.local.json`
+pub fn override_path(path: &Path) -> PathBuf {
+  let stem = path.file_stem().map_or("config".into(), |stem| stem.to_string_lossy());
+  match path.extension() {
+    Some(extension) => path.with_file_name(format!("{}.local.{}", stem, extension.to_string_lossy())),
+    None => path.with_file_name(format!("{}.local", stem))
+  }
+}
+
+/// Finds the default config file, preferring `config.json` if several formats are present
+pub fn default_path() -> PathBuf {
+  Format::ALL.iter()
+    .map(|format| Path::new(CONFIG_PATH).with_extension(format.extension()))
+    .find(|path| path.exists())
+    .unwrap_or_else(|| PathBuf::from(CONFIG_PATH))
+}
+
+/// Renders the merged config at the given path, with secrets redacted. `${VAR}` references are
+/// shown as written, so values taken from the environment aren't printed, but they still have to
+/// be set.
+pub fn effective_config(path: impl AsRef<Path>, format: Option<Format>) -> Result<String, Error> {
+  let path = path.as_ref();
+  let format = format_for(path, format);
+  let (mut value, _) = load_layers(path, format, false)?;
+  secrets::interpolate(&mut value.clone())?;
+  if let Some(token) = value.get_mut("token") {
+    *token = serde_json::Value::String("<redacted>".to_owned());
+  };
+
+  format.render(&value)
+}
+
+fn format_for(path: &Path, format: Option<Format>) -> Format {
+  format.or_else(|| Format::from_path(path)).unwrap_or(Format::Json)
+}
+
+/// Reads the base config and its override (if present), upgrading and merging them, but leaving
+/// `${VAR}` references for the caller to interpolate.
+/// A base written in an older layout is upgraded on disk if `write_back` is set. The override
+/// only holds part of a config, so it isn't versioned and is merged as it is.
+/// Also returns which layers contained a token.
+fn load_layers(path: &Path, format: Format, write_back: bool) -> Result<(serde_json::Value, Vec<PathBuf>), Error> {
+  let mut value = format.parse(&fs::read_to_string(path)?)?;
+  match write_back {
+    true => migrate::migrate_file(Kind::Config, path, format, &mut value)?,
+    false => { migrate::migrate(Kind::Config, &mut value)?; }
+  };
+
+  let mut token_layers = Vec::new();
+  if holds_token(&value) {
+    token_layers.push(path.to_owned());
+  };
+
+  let override_path = override_path(path);
+  match fs::read_to_string(&override_path) {
+    Ok(contents) => {
+      let mut over = format.parse(&contents)?;
+      // Older builds stamped overrides with a version too, the base's is the one that counts
+      over.as_object_mut()
+        .ok_or(Error::Custom("expected a map at the top level of the override file"))?
+        .remove("version");
+
+      if holds_token(&over) {
+        token_layers.push(override_path);
+      };
+
+      merge(&mut value, over);
+    },
+    Err(err) if err.kind() == io::ErrorKind::NotFound => (),
+    Err(err) => return Err(err.into())
+  };
+
+  Ok((value, token_layers))
+}
+
+/// Whether a raw config layer has a token written out in it, rather than none, `null` or a
+/// `${VAR}` reference
+fn holds_token(layer: &serde_json::Value) -> bool {
+  match layer.get("token") {
+    Some(serde_json::Value::String(token)) => !token.is_empty() && !token.contains("${"),
+    _ => false
+  }
+}
+
+/// Lookup tables built once per config, mapping to indexes into the config's lists
+#[derive(Debug, Default)]
+struct ConfigIndex {
+  rank_roles: HashMap<RoleId, usize>,
+  rank_names: HashMap<String, usize>,
+  rank_names_lower: HashMap<String, usize>,
+  position_roles: HashMap<RoleId, usize>,
+  position_names: HashMap<String, usize>,
+  admin_roles: HashSet<RoleId>,
+  menu_emojis: HashMap<ReactionType, usize>,
+  menu_names: HashMap<String, usize>,
+  assignables_lower: HashMap<String, RoleId>,
+  restricted_roles: HashMap<RoleId, usize>
+}
+
+impl ConfigIndex {
+  fn new(config: &Config) -> ConfigIndex {
+    let mut index = ConfigIndex::default();
+    // Earlier entries win where names or roles are repeated, like the scans they replace
+    for (i, rank) in config.ranks.iter().enumerate().rev() {
+      index.rank_roles.insert(rank.role, i);
+      index.rank_names.insert(rank.name.clone(), i);
+      index.rank_names_lower.insert(rank.name.to_lowercase(), i);
+    };
+
+    for (i, position) in config.positions.iter().enumerate().rev() {
+      index.position_roles.insert(position.role, i);
+      index.position_names.insert(position.name.clone(), i);
+      if position.admin {
+        index.admin_roles.insert(position.role);
+      };
+    };
+
+    for (i, role_position) in config.role_menu_positions.iter().enumerate().rev() {
+      index.menu_emojis.insert(role_position.emoji.clone(), i);
+      index.menu_names.insert(role_position.name.clone(), i);
+    };
+
+    for (name, &role) in config.assignable.iter().rev() {
+      index.assignables_lower.insert(name.to_lowercase(), role);
+    };
+
+    for (name, rank_name) in config.restricted_assignable.iter() {
+      if let (Some(&role), Some(&rank_index)) = (config.assignable.get(name), index.rank_names.get(rank_name)) {
+        index.restricted_roles.insert(role, rank_index);
+      };
+    };
+
+    index
+  }
+}
 
 pub struct ConfigContainer;
 
 impl TypeMapKey for ConfigContainer {
-  type Value = Arc<RwLock<ConfigFile>>;
+  type Value = Arc<SharedConfig>;
+}
+
+/// The live config. Readers take a snapshot, which never blocks and stays valid for as long
+/// as they hold it, while edits and reloads publish a new snapshot in its place.
+#[derive(Debug)]
+pub struct SharedConfig {
+  current: ArcSwap<ConfigFile>,
+  /// Held while a new config is being made, so that concurrent edits don't overwrite each other
+  writer: Mutex<()>
+}
+
+impl SharedConfig {
+  pub fn new(config: ConfigFile) -> SharedConfig {
+    SharedConfig { current: ArcSwap::from_pointee(config), writer: Mutex::new(()) }
+  }
+
+  /// Takes a snapshot of the current config
+  pub fn load(&self) -> Arc<ConfigFile> {
+    self.current.load_full()
+  }
+
+  /// Makes a new config from the current one and publishes it, unless `f` fails
+  pub async fn update<T, F>(&self, f: F) -> Result<T, Error>
+  where F: FnOnce(&ConfigFile) -> Result<(ConfigFile, T), Error> {
+    let _writer = self.writer.lock().await;
+    let (config, value) = f(&self.current.load())?;
+    self.current.store(Arc::new(config));
+    Ok(value)
+  }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Config {
+  /// The version of the config layout, used to upgrade older config files
+  pub version: u32,
   /// A list of user ids that have absolute authority
   pub owners: HashSet<UserId>,
-  /// Token used to sign the bot in
-  pub token: String,
+  /// Token used to sign the bot in, prefer providing this through the
+  /// `SENTINEL_TOKEN` environment variable, `secrets.json` or `token_file` instead
+  #[serde(default)]
+  pub token: Option<String>,
+  /// A file containing only the token used to sign the bot in
+  #[serde(default)]
+  pub token_file: Option<PathBuf>,
   /// The only guild sentinel will respond in
   pub guild: GuildId,
+  /// Whether to leave any other guild the bot is in or gets invited to
+  #[serde(default)]
+  pub leave_other_guilds: bool,
   /// Rank to give to people if they need a rank and have none
   pub default_rank: String,
   /// The rank ladder used in `promote` and `demote`
@@ -38,6 +483,9 @@ pub struct Config {
   pub positions: Vec<Position>,
   /// Roles which may be assigned to users
   pub assignable: BTreeMap<String, RoleId>,
+  /// Assignable roles that only members ranked above the given rank may assign or unassign
+  #[serde(default)]
+  pub restricted_assignable: BTreeMap<String, String>,
   /// The channel and message id of the role reaction menu
   pub role_menu: (ChannelId, MessageId),
   /// A map determining which emoji grants which position role
@@ -47,108 +495,133 @@ pub struct Config {
   /// Channel to paste greetings into
   pub greeting_channel: ChannelId,
   /// Text to use for a greeting message
-  pub greeting: Vec<String>
+  pub greeting: Vec<String>,
+  /// Local address to serve Prometheus metrics and health checks on, if any.
+  /// Configs written before health checks were added call it `metrics_address`.
+  #[serde(default, alias = "metrics_address")]
+  pub http_address: Option<SocketAddr>,
+  /// Whether to send readiness, watchdog and stopping notifications to systemd
+  #[serde(default)]
+  pub systemd_notify: bool,
+  /// Seconds to wait for in-flight commands and reactions when shutting down
+  #[serde(default = "default_shutdown_timeout")]
+  pub shutdown_timeout: u64,
+  /// How many rotating persist backups to keep in the `backups` directory, 0 disables them
+  #[serde(default = "default_persist_backups")]
+  pub persist_backups: usize,
+  /// The channel and text of a message to post when the bot goes offline, if any
+  #[serde(default)]
+  pub offline_notice: Option<(ChannelId, String)>,
+  /// How recommendations for senior ranks are reviewed, if they are
+  #[serde(default)]
+  pub review: Option<Review>,
+  /// Who may run what, keyed by command (like `setrank` or `config rank`) or by group
+  /// (like `admin`). Commands without an entry use their group's entry, then the built-in default.
+  #[serde(default)]
+  pub permissions: BTreeMap<String, Permission>
 }
 
 impl Config {
-  pub fn is_admin_role(&self, role_id: RoleId) -> bool {
-    self.positions.iter()
-      .any(|position| position.admin && position.role == role_id)
-  }
+  /// Checks that every name referenced in the config refers to something that exists
+  pub fn validate(&self) -> Result<(), Error> {
+    let index = ConfigIndex::new(self);
+    let has_rank = |name: &str| index.rank_names.contains_key(name);
+    let has_position = |name: &str| index.position_names.contains_key(name);
+    if !has_rank(&self.default_rank) {
+      return Err(Error::Custom("default rank is not in the rank list"));
+    };
 
-  pub fn is_role_menu_reaction(&self, react: &Reaction) -> bool {
-    self.role_menu == (react.channel_id, react.message_id) &&
-    self.role_menu_positions.iter()
-      .any(|role_position| role_position.emoji == react.emoji)
-  }
+    for role_position in self.role_menu_positions.iter() {
+      if !has_position(&role_position.name) {
+        return Err(Error::Custom("role menu refers to a position that does not exist"));
+      };
+    };
 
-  pub fn should_grant_position(&self, position: Option<&Position>) -> bool {
-    if let Some(position) = position {
-      self.role_menu_positions.iter()
-        .any(|role_position| role_position.name == position.name)
-    } else {
-      true
-    }
-  }
+    for greetable_position in self.greetable_positions.iter() {
+      if !has_position(greetable_position) {
+        return Err(Error::Custom("greetable position does not exist"));
+      };
+    };
 
-  pub fn get_role_menu_position(&self, emoji: &ReactionType) -> Option<&RoleMenuPosition> {
-    self.role_menu_positions.iter()
-      .find(|role_position| role_position.emoji == *emoji)
-  }
+    for (assignable_name, rank_name) in self.restricted_assignable.iter() {
+      if !self.assignable.contains_key(assignable_name) {
+        return Err(Error::Custom("restricted assignable does not exist"));
+      };
 
-  pub fn get_rank_by_name_loose(&self, rank_name: &str) -> Option<&Rank> {
-    let rank_name = rank_name.to_lowercase();
-    self.ranks.iter()
-      .find(|rank| rank.name.to_lowercase() == rank_name)
-  }
+      if !has_rank(rank_name) {
+        return Err(Error::Custom("restricted assignable refers to a rank that does not exist"));
+      };
+    };
 
-  pub fn get_rank_by_name(&self, rank_name: &str) -> Option<&Rank> {
-    self.ranks.iter()
-      .find(|rank| rank.name == rank_name)
-  }
+    if let Some(review) = &self.review {
+      if review.ranks.iter().any(|rank| !has_rank(rank)) {
+        return Err(Error::Custom("review refers to a rank that does not exist"));
+      };
 
-  pub fn get_member_ranks(&self, roles: &[RoleId]) -> Vec<&Rank> {
-    roles.iter()
-      .filter_map(|role| {
-        self.ranks.iter()
-          .find_map(|rank| match rank.role == *role {
-            true => Some(rank),
-            false => None
-          })
-      })
-      .collect()
-  }
+      if !has_rank(&review.approver_rank) {
+        return Err(Error::Custom("review approver rank does not exist"));
+      };
 
-  /*pub fn get_position_by_name_loose(&self, position_name: &str) -> Option<&Position> {
-    let position_name = position_name.to_lowercase();
-    self.positions.iter()
-      .find(|position| position.name.to_lowercase() == position_name)
-  }*/
+      if review.approvals == 0 {
+        return Err(Error::Custom("review needs at least one approval"));
+      };
+    };
 
-  pub fn get_position_by_name(&self, position_name: &str) -> Option<&Position> {
-    self.positions.iter()
-      .find(|position| position.name == position_name)
-  }
+    for permission in self.permissions.values() {
+      if permission.positions.iter().any(|position| !has_position(position)) {
+        return Err(Error::Custom("permissions refer to a position that does not exist"));
+      };
 
-  pub fn get_member_positions(&self, roles: &[RoleId]) -> Vec<&Position> {
-    roles.iter()
-      .filter_map(|role| {
-        self.positions.iter()
-          .find_map(|position| match position.role == *role {
-            true => Some(position),
-            false => None
-          })
-      })
-      .collect()
-  }
+      if let Some(min_rank) = &permission.min_rank {
+        if !has_rank(min_rank) {
+          return Err(Error::Custom("permissions refer to a rank that does not exist"));
+        };
+      };
+    };
 
-  pub fn get_role_menu_emoji(&self, position_name: &str) -> Option<ReactionType> {
-    self.role_menu_positions.iter()
-      .find_map(|role_position| match role_position.name == position_name {
-        true => Some(role_position.emoji.clone()),
-        false => None
-      })
+    Ok(())
   }
 
-  pub fn get_higher_rank(&self, rank_name: &str) -> Option<&Rank> {
-    let index = self.ranks.iter()
-      .position(|rank| rank.name == rank_name)?;
-    self.ranks.get(index + 1)
+  /// Lists the names of the fields that differ between this config and another
+  pub fn changed_fields(&self, other: &Config) -> Vec<&'static str> {
+    let mut changed = Vec::new();
+    macro_rules! compare {
+      ($($field:ident),* $(,)?) => {
+        $(if self.$field != other.$field { changed.push(stringify!($field)) };)*
+      };
+    }
+
+    compare!(
+      version, owners, token, token_file, guild, leave_other_guilds, default_rank, ranks, positions,
+      assignable, restricted_assignable, role_menu, role_menu_positions, greetable_positions, greeting_channel,
+      greeting, http_address, systemd_notify, shutdown_timeout, persist_backups,
+      offline_notice, review, permissions
+    );
+
+    changed
   }
 
-  pub fn get_lower_rank(&self, rank_name: &str) -> Option<&Rank> {
-    let index = self.ranks.iter()
-      .position(|rank| rank.name == rank_name)?;
-    self.ranks.get(index - 1)
+  /// Finds the bot token, checking in order the `SENTINEL_TOKEN` environment variable,
+  /// the secrets file, the token file and finally the config itself
+  pub fn resolve_token(&self) -> Result<String, Error> {
+    if let Ok(token) = std::env::var(TOKEN_VAR) {
+      return Ok(token);
+    };
+
+    if let Some(token) = Secrets::load()?.token {
+      return Ok(token);
+    };
+
+    if let Some(token_file) = &self.token_file {
+      return secrets::read_token_file(token_file);
+    };
+
+    self.token.clone().ok_or(Error::Custom("no bot token configured"))
   }
 
-  pub fn get_assignable_loose(&self, assignable_name: &str) -> Option<RoleId> {
-    let assignable_name = assignable_name.to_lowercase();
-    self.assignable.iter()
-      .find_map(|(name, role_id)| match name.to_lowercase() == assignable_name {
-        true => Some(role_id.clone()),
-        false => None
-      })
+  /// Whether something that happened in this guild (or outside of any guild) is in the configured guild
+  pub fn is_configured_guild(&self, guild_id: Option<GuildId>) -> bool {
+    guild_id == Some(self.guild)
   }
 
   pub fn get_greeting(&self) -> String {
@@ -156,6 +629,14 @@ impl Config {
   }
 }
 
+pub fn default_shutdown_timeout() -> u64 {
+  30
+}
+
+pub fn default_persist_backups() -> usize {
+  10
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Position {
   pub name: String,
@@ -170,8 +651,226 @@ pub struct Rank {
   pub role: RoleId
 }
 
+/// How recommendations for senior ranks are voted on
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Review {
+  /// The channel to post ballots in
+  pub channel: ChannelId,
+  /// Ranks that can only be given through an approved recommendation, except by owners
+  pub ranks: HashSet<String>,
+  /// How many approvals (or denials) decide a recommendation
+  pub approvals: usize,
+  /// Name of the lowest rank whose votes count
+  pub approver_rank: String
+}
+
+/// Who may run a command or group of commands. Owners may always run everything,
+/// anyone else needs to match at least one of these.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Permission {
+  /// Anyone may run it
+  #[serde(default)]
+  pub everyone: bool,
+  /// Members with an admin position or the Administrator permission may run it
+  #[serde(default)]
+  pub admins: bool,
+  #[serde(default)]
+  pub users: HashSet<UserId>,
+  #[serde(default)]
+  pub roles: HashSet<RoleId>,
+  /// Names of positions whose members may run it
+  #[serde(default)]
+  pub positions: HashSet<String>,
+  /// Name of the lowest rank that may run it
+  #[serde(default)]
+  pub min_rank: Option<String>
+}
+
+impl Permission {
+  pub fn everyone() -> Permission {
+    Permission { everyone: true, ..Permission::default() }
+  }
+
+  pub fn admins() -> Permission {
+    Permission { admins: true, ..Permission::default() }
+  }
+}
+
+impl fmt::Display for Permission {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    if self.everyone {
+      return write!(f, "everyone");
+    };
+
+    let mut parts = vec!["owners".to_owned()];
+    if self.admins {
+      parts.push("admins".to_owned());
+    };
+
+    let mut users = self.users.iter().map(|user_id| format!("user {}", user_id)).collect::<Vec<String>>();
+    let mut roles = self.roles.iter().map(|role_id| format!("role {}", role_id)).collect::<Vec<String>>();
+    let mut positions = self.positions.iter().cloned().collect::<Vec<String>>();
+    users.sort();
+    roles.sort();
+    positions.sort();
+    parts.extend(users);
+    parts.extend(roles);
+    parts.extend(positions);
+    if let Some(min_rank) = &self.min_rank {
+      parts.push(format!("{} and above", min_rank));
+    };
+
+    write!(f, "{}", parts.join(", "))
+  }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct RoleMenuPosition {
   pub emoji: ReactionType,
   pub name: String
 }
+
+#[cfg(test)]
+mod tests {
+  use serde_json::{json, Value};
+
+  use super::*;
+  use crate::data::fixtures;
+  use crate::data::migrate::CONFIG_VERSION;
+
+  /// Writes the fixture as a base config, with references in its greeting, and an override
+  /// next to it into a fresh directory, returning the path of the base
+  fn write_layers(name: &str, over: Value) -> PathBuf {
+    // Tests run in parallel, so the variable is only ever set once, to the same value
+    static SET_UNIT: std::sync::Once = std::sync::Once::new();
+    SET_UNIT.call_once(|| std::env::set_var("SENTINEL_TEST_UNIT", "A3F"));
+    let dir = std::env::temp_dir().join(format!("sentinel-test-{}-{}", std::process::id(), name));
+    fs::create_dir_all(&dir).unwrap();
+    let mut base = fixtures::config_0_3_0();
+    base["version"] = json!(CONFIG_VERSION);
+    base["greeting"] = json!(["Welcome {mention} to ${SENTINEL_TEST_UNIT}!", "Bring $${5}"]);
+    let path = dir.join("config.json");
+    fs::write(&path, Format::Json.render(&base).unwrap()).unwrap();
+    fs::write(override_path(&path), Format::Json.render(&over).unwrap()).unwrap();
+    path
+  }
+
+  fn read_layers(path: &Path) -> (Value, Value) {
+    let read = |path: &Path| Format::Json.parse(&fs::read_to_string(path).unwrap()).unwrap();
+    (read(path), read(&override_path(path)))
+  }
+
+  /// Edits a config and checks that loading it again from disk gives the edited config
+  fn edit(config: &ConfigFile, f: impl FnOnce(&mut Config)) -> ConfigFile {
+    let (edited, _) = config.edit(|config| {
+      f(config);
+      Ok(())
+    }).unwrap();
+    assert_eq!(edited.reopen().unwrap().config, edited.config);
+    edited
+  }
+
+  fn rank_names(config: &ConfigFile) -> Vec<&str> {
+    config.ranks.iter().map(|rank| rank.name.as_str()).collect()
+  }
+
+  #[test]
+  fn edits_keep_references() {
+    let over = json!({
+      "guild": 320000000000000009u64,
+      "offline_notice": [360000000000000003u64, "${SENTINEL_TEST_UNIT} is offline"]
+    });
+    let path = write_layers("references", over.clone());
+    let config = ConfigFile::open(&path, None).unwrap();
+    assert_eq!(config.greeting, vec!["Welcome {mention} to A3F!".to_owned(), "Bring ${5}".to_owned()]);
+    assert_eq!(config.guild, GuildId(320000000000000009));
+
+    let config = edit(&config, |config| {
+      config.greeting.push("Have fun".to_owned());
+      config.shutdown_timeout = 5;
+    });
+    let (base, new_over) = read_layers(&path);
+    assert_eq!(base["greeting"], json!(["Welcome {mention} to ${SENTINEL_TEST_UNIT}!", "Bring $${5}", "Have fun"]));
+    assert_eq!(base["guild"], json!(320000000000000001u64));
+    // Neither layer set the timeout, so it's new and goes to the override
+    assert_eq!(base.get("shutdown_timeout"), None);
+    assert_eq!(new_over["shutdown_timeout"], json!(5));
+    assert_eq!(new_over["offline_notice"], over["offline_notice"]);
+
+    edit(&config, |config| config.offline_notice = Some((ChannelId(360000000000000003), "Back soon".to_owned())));
+    let (new_base, new_over) = read_layers(&path);
+    assert_eq!(new_base, base);
+    assert_eq!(new_over["offline_notice"], json!([360000000000000003u64, "Back soon"]));
+
+    fs::remove_dir_all(path.parent().unwrap()).unwrap();
+  }
+
+  #[test]
+  fn edits_remove_and_move_entries_in_both_layers() {
+    let path = write_layers("entries", json!({
+      "ranks": [
+        { "name": "Corporal", "role": 330000000000000012u64 },
+        { "name": "Captain", "role": 330000000000000014u64 }
+      ],
+      "positions": [{ "name": "Staff", "admin": false }],
+      "assignable": { "Pilot": 350000000000000012u64 }
+    }));
+    let config = ConfigFile::open(&path, None).unwrap();
+    assert_eq!(rank_names(&config), vec!["Private", "Corporal", "Sergeant", "Captain"]);
+
+    let config = edit(&config, |config| config.ranks.retain(|rank| rank.name != "Sergeant"));
+    assert_eq!(rank_names(&config), vec!["Private", "Corporal", "Captain"]);
+
+    // Captain only exists in the override, which can't put it before the base's ranks
+    let moved = config.edit(|config| {
+      let captain = config.ranks.pop().unwrap();
+      config.ranks.insert(0, captain);
+      Ok(())
+    });
+    assert!(moved.is_err());
+
+    let config = edit(&config, |config| config.ranks.swap(0, 1));
+    assert_eq!(rank_names(&config), vec!["Corporal", "Private", "Captain"]);
+
+    let config = edit(&config, |config| config.ranks.retain(|rank| rank.name != "Corporal"));
+    assert_eq!(rank_names(&config), vec!["Private", "Captain"]);
+
+    let config = edit(&config, |config| config.ranks.push(Rank { name: "Major".to_owned(), role: RoleId(330000000000000015) }));
+    assert_eq!(rank_names(&config), vec!["Private", "Captain", "Major"]);
+
+    let config = edit(&config, |config| { config.assignable.remove("Pilot"); });
+    assert_eq!(config.assignable.keys().collect::<Vec<&String>>(), vec!["Medic"]);
+
+    let config = edit(&config, |config| config.positions[2].admin = true);
+    assert!(config.get_position_by_name("Staff").unwrap().admin);
+
+    let (base, over) = read_layers(&path);
+    assert_eq!(base["ranks"], json!([{ "name": "Private", "role": 330000000000000001u64 }]));
+    assert_eq!(over["ranks"], json!([
+      { "name": "Captain", "role": 330000000000000014u64 },
+      { "name": "Major", "role": 330000000000000015u64 }
+    ]));
+    assert_eq!(over["positions"], json!([{ "name": "Staff", "admin": true }]));
+    assert_eq!(base["greeting"][0], json!("Welcome {mention} to ${SENTINEL_TEST_UNIT}!"));
+
+    fs::remove_dir_all(path.parent().unwrap()).unwrap();
+  }
+
+  #[test]
+  fn only_written_out_tokens_count_as_held() {
+    assert!(holds_token(&json!({ "token": "abc.def" })));
+    assert!(!holds_token(&json!({})));
+    assert!(!holds_token(&json!({ "token": null })));
+    assert!(!holds_token(&json!({ "token": "" })));
+    assert!(!holds_token(&json!({ "token": "${SENTINEL_TOKEN}" })));
+  }
+
+  #[test]
+  fn metrics_address_is_read_as_http_address() {
+    let mut value = fixtures::config_0_3_0();
+    migrate::migrate(Kind::Config, &mut value).unwrap();
+    value["metrics_address"] = json!("127.0.0.1:9184");
+    let config: Config = serde_json::from_value(value).unwrap();
+    assert_eq!(config.http_address, Some("127.0.0.1:9184".parse().unwrap()));
+  }
+}
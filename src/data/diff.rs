@@ -0,0 +1,399 @@
+use std::fmt;
+
+use serenity::model::{
+  channel::ReactionType,
+  id::RoleId
+};
+
+use super::config::{Config, Rank, Position, RESTART_FIELDS};
+
+/// Fields that `ConfigDiff` describes in detail rather than only by name
+const DESCRIBED_FIELDS: &[&str] = &[
+  "ranks", "positions", "assignable", "role_menu_positions", "greeting"
+];
+
+/// A semantic description of the differences between two configs
+#[derive(Debug, Default)]
+pub struct ConfigDiff {
+  pub ranks_added: Vec<String>,
+  pub ranks_removed: Vec<Rank>,
+  /// The new rank ladder, if the relative order of existing ranks changed
+  pub ranks_reordered: Option<Vec<String>>,
+  /// Rank name, old role, new role
+  pub rank_roles_changed: Vec<(String, RoleId, RoleId)>,
+  pub positions_added: Vec<String>,
+  pub positions_removed: Vec<Position>,
+  /// Position name, flag name, old value, new value
+  pub position_flags_changed: Vec<(String, &'static str, bool, bool)>,
+  /// Position name, old role, new role
+  pub position_roles_changed: Vec<(String, RoleId, RoleId)>,
+  /// Role ID and name of added assignables
+  pub assignables_added: Vec<(String, RoleId)>,
+  /// Role ID and name of removed assignables
+  pub assignables_removed: Vec<(String, RoleId)>,
+  /// Old name and new name of assignables whose role stayed the same
+  pub assignables_renamed: Vec<(String, String)>,
+  /// Position name, old emoji (if any), new emoji (if any)
+  pub menu_emojis_changed: Vec<(String, Option<ReactionType>, Option<ReactionType>)>,
+  pub greeting_changed: bool,
+  /// Names of any other top-level fields that changed
+  pub other_fields: Vec<&'static str>,
+  /// Keys of the new config's permission table that don't match any command or group. Left for
+  /// the caller to fill in, since the config doesn't know what commands there are
+  pub unknown_permissions: Vec<String>
+}
+
+impl ConfigDiff {
+  pub fn new(old: &Config, new: &Config) -> ConfigDiff {
+    let mut diff = ConfigDiff::default();
+
+    // Ranks
+    for rank in new.ranks.iter() {
+      match find_rank(old, &rank.name) {
+        Some(old_rank) if old_rank.role != rank.role => {
+          diff.rank_roles_changed.push((rank.name.clone(), old_rank.role, rank.role));
+        },
+        Some(_) => (),
+        None => diff.ranks_added.push(rank.name.clone())
+      };
+    };
+
+    for rank in old.ranks.iter() {
+      if find_rank(new, &rank.name).is_none() {
+        diff.ranks_removed.push(rank.clone());
+      };
+    };
+
+    let old_order = old.ranks.iter()
+      .filter(|rank| find_rank(new, &rank.name).is_some())
+      .map(|rank| rank.name.as_str());
+    let new_order = new.ranks.iter()
+      .filter(|rank| find_rank(old, &rank.name).is_some())
+      .map(|rank| rank.name.as_str());
+    if !old_order.eq(new_order) {
+      diff.ranks_reordered = Some(new.ranks.iter().map(|rank| rank.name.clone()).collect());
+    };
+
+    // Positions
+    for position in new.positions.iter() {
+      match find_position(old, &position.name) {
+        Some(old_position) => {
+          if old_position.ranked != position.ranked {
+            diff.position_flags_changed.push((position.name.clone(), "ranked", old_position.ranked, position.ranked));
+          };
+
+          if old_position.admin != position.admin {
+            diff.position_flags_changed.push((position.name.clone(), "admin", old_position.admin, position.admin));
+          };
+
+          if old_position.role != position.role {
+            diff.position_roles_changed.push((position.name.clone(), old_position.role, position.role));
+          };
+        },
+        None => diff.positions_added.push(position.name.clone())
+      };
+    };
+
+    for position in old.positions.iter() {
+      if find_position(new, &position.name).is_none() {
+        diff.positions_removed.push(position.clone());
+      };
+    };
+
+    // Assignables
+    for (name, &role) in new.assignable.iter() {
+      if old.assignable.contains_key(name) { continue };
+      match old.assignable.iter().find(|&(old_name, &old_role)| old_role == role && !new.assignable.contains_key(old_name)) {
+        Some((old_name, _)) => diff.assignables_renamed.push((old_name.clone(), name.clone())),
+        None => diff.assignables_added.push((name.clone(), role))
+      };
+    };
+
+    for (name, &role) in old.assignable.iter() {
+      let renamed = diff.assignables_renamed.iter().any(|(old_name, _)| old_name == name);
+      if !new.assignable.contains_key(name) && !renamed {
+        diff.assignables_removed.push((name.clone(), role));
+      };
+    };
+
+    // Role menu
+    for role_position in new.role_menu_positions.iter() {
+      let old_emoji = find_menu_emoji(old, &role_position.name);
+      if old_emoji.as_ref() != Some(&role_position.emoji) {
+        diff.menu_emojis_changed.push((role_position.name.clone(), old_emoji, Some(role_position.emoji.clone())));
+      };
+    };
+
+    for role_position in old.role_menu_positions.iter() {
+      if find_menu_emoji(new, &role_position.name).is_none() {
+        diff.menu_emojis_changed.push((role_position.name.clone(), Some(role_position.emoji.clone()), None));
+      };
+    };
+
+    diff.greeting_changed = old.greeting != new.greeting;
+    diff.other_fields = old.changed_fields(new).into_iter()
+      .filter(|field| !DESCRIBED_FIELDS.contains(field))
+      .collect();
+
+    diff
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.ranks_added.is_empty() &&
+    self.ranks_removed.is_empty() &&
+    self.ranks_reordered.is_none() &&
+    self.rank_roles_changed.is_empty() &&
+    self.positions_added.is_empty() &&
+    self.positions_removed.is_empty() &&
+    self.position_flags_changed.is_empty() &&
+    self.position_roles_changed.is_empty() &&
+    self.assignables_added.is_empty() &&
+    self.assignables_removed.is_empty() &&
+    self.assignables_renamed.is_empty() &&
+    self.menu_emojis_changed.is_empty() &&
+    !self.greeting_changed &&
+    self.other_fields.is_empty()
+  }
+
+  /// Lists changes that affect members who currently hold roles, given each member's roles,
+  /// along with any unknown permission entries
+  pub fn warnings(&self, members: &[Vec<RoleId>]) -> Vec<String> {
+    let holders = |role: RoleId| members.iter()
+      .filter(|roles| roles.contains(&role))
+      .count();
+    let mut warnings = Vec::new();
+
+    for rank in self.ranks_removed.iter() {
+      let count = holders(rank.role);
+      if count > 0 {
+        warnings.push(format!("Removed rank `{}` is still held by {} member(s)", rank.name, count));
+      };
+    };
+
+    for position in self.positions_removed.iter() {
+      let count = holders(position.role);
+      if count > 0 {
+        warnings.push(format!("Removed position `{}` is still held by {} member(s)", position.name, count));
+      };
+    };
+
+    for (name, old_role, _) in self.rank_roles_changed.iter() {
+      let count = holders(*old_role);
+      if count > 0 {
+        warnings.push(format!("{} member(s) still hold the old role of rank `{}`", count, name));
+      };
+    };
+
+    for (name, old_role, _) in self.position_roles_changed.iter() {
+      let count = holders(*old_role);
+      if count > 0 {
+        warnings.push(format!("{} member(s) still hold the old role of position `{}`", count, name));
+      };
+    };
+
+    for (name, role) in self.assignables_removed.iter() {
+      let count = holders(*role);
+      if count > 0 {
+        warnings.push(format!("Removed assignable `{}` is still held by {} member(s)", name, count));
+      };
+    };
+
+    for (name, flag, _, new) in self.position_flags_changed.iter() {
+      if *flag == "ranked" {
+        let what = match new {
+          true => "will need a rank they may not have",
+          false => "may still have ranks they should not"
+        };
+
+        warnings.push(format!("Members with position `{}` {}", name, what));
+      };
+    };
+
+    for key in self.unknown_permissions.iter() {
+      warnings.push(format!("Permission entry `{}` does not match any command or group", key));
+    };
+
+    warnings
+  }
+}
+
+// Diffs are made between plain configs, which have no index to look things up in
+
+fn find_rank<'a>(config: &'a Config, name: &str) -> Option<&'a Rank> {
+  config.ranks.iter().find(|rank| rank.name == name)
+}
+
+fn find_position<'a>(config: &'a Config, name: &str) -> Option<&'a Position> {
+  config.positions.iter().find(|position| position.name == name)
+}
+
+fn find_menu_emoji(config: &Config, name: &str) -> Option<ReactionType> {
+  config.role_menu_positions.iter()
+    .find(|role_position| role_position.name == name)
+    .map(|role_position| role_position.emoji.clone())
+}
+
+impl fmt::Display for ConfigDiff {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    if self.is_empty() {
+      return writeln!(f, "No changes");
+    };
+
+    for name in self.ranks_added.iter() {
+      writeln!(f, "+ Rank `{}` added", name)?;
+    };
+
+    for rank in self.ranks_removed.iter() {
+      writeln!(f, "- Rank `{}` removed", rank.name)?;
+    };
+
+    if let Some(ranks) = &self.ranks_reordered {
+      writeln!(f, "~ Ranks reordered: {}", ranks.join(" < "))?;
+    };
+
+    for (name, old, new) in self.rank_roles_changed.iter() {
+      writeln!(f, "~ Rank `{}`: role changed from {} to {}", name, old, new)?;
+    };
+
+    for name in self.positions_added.iter() {
+      writeln!(f, "+ Position `{}` added", name)?;
+    };
+
+    for position in self.positions_removed.iter() {
+      writeln!(f, "- Position `{}` removed", position.name)?;
+    };
+
+    for (name, flag, old, new) in self.position_flags_changed.iter() {
+      writeln!(f, "~ Position `{}`: `{}` changed from {} to {}", name, flag, old, new)?;
+    };
+
+    for (name, old, new) in self.position_roles_changed.iter() {
+      writeln!(f, "~ Position `{}`: role changed from {} to {}", name, old, new)?;
+    };
+
+    for (name, _) in self.assignables_added.iter() {
+      writeln!(f, "+ Assignable `{}` added", name)?;
+    };
+
+    for (name, _) in self.assignables_removed.iter() {
+      writeln!(f, "- Assignable `{}` removed", name)?;
+    };
+
+    for (old_name, new_name) in self.assignables_renamed.iter() {
+      writeln!(f, "~ Assignable `{}` renamed to `{}`", old_name, new_name)?;
+    };
+
+    for (name, old, new) in self.menu_emojis_changed.iter() {
+      match (old, new) {
+        (None, Some(new)) => writeln!(f, "+ Menu emoji {} added for `{}`", new, name)?,
+        (Some(old), None) => writeln!(f, "- Menu emoji {} removed for `{}`", old, name)?,
+        (Some(old), Some(new)) => writeln!(f, "~ Menu emoji for `{}` changed from {} to {}", name, old, new)?,
+        (None, None) => ()
+      };
+    };
+
+    if self.greeting_changed {
+      writeln!(f, "~ Greeting text changed")?;
+    };
+
+    for field in self.other_fields.iter() {
+      match RESTART_FIELDS.contains(field) {
+        true => writeln!(f, "~ `{}` changed (requires restart)", field)?,
+        false => writeln!(f, "~ `{}` changed", field)?
+      };
+    };
+
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::data::fixtures::config;
+
+  #[test]
+  fn identical_configs_have_no_changes() {
+    let diff = ConfigDiff::new(&config(), &config());
+    assert!(diff.is_empty());
+    assert_eq!(diff.to_string(), "No changes\n");
+  }
+
+  #[test]
+  fn ranks_added_removed_and_reordered() {
+    let old = config();
+    let mut new = config();
+    let corporal = new.ranks.remove(1);
+    new.ranks.push(Rank { name: "Lieutenant".to_owned(), role: RoleId(330000000000000004) });
+    new.ranks.swap(0, 1);
+
+    let diff = ConfigDiff::new(&old, &new);
+    assert_eq!(diff.ranks_added, vec!["Lieutenant".to_owned()]);
+    assert_eq!(diff.ranks_removed, vec![corporal]);
+    assert_eq!(diff.ranks_reordered, Some(vec!["Sergeant".to_owned(), "Private".to_owned(), "Lieutenant".to_owned()]));
+    assert!(diff.other_fields.is_empty());
+  }
+
+  #[test]
+  fn role_changes_are_reported() {
+    let old = config();
+    let mut new = config();
+    new.ranks[1].role = RoleId(1);
+    new.positions[0].role = RoleId(2);
+
+    let diff = ConfigDiff::new(&old, &new);
+    assert_eq!(diff.rank_roles_changed, vec![("Corporal".to_owned(), RoleId(330000000000000002), RoleId(1))]);
+    assert_eq!(diff.position_roles_changed, vec![("Rifleman".to_owned(), RoleId(340000000000000001), RoleId(2))]);
+    assert!(diff.ranks_added.is_empty() && diff.ranks_removed.is_empty() && diff.ranks_reordered.is_none());
+
+    let text = diff.to_string();
+    assert!(text.contains("Rank `Corporal`: role changed from 330000000000000002 to 1"));
+    assert!(text.contains("Position `Rifleman`: role changed from 340000000000000001 to 2"));
+
+    let members = vec![vec![RoleId(330000000000000002)], vec![RoleId(1)]];
+    assert_eq!(diff.warnings(&members), vec!["1 member(s) still hold the old role of rank `Corporal`".to_owned()]);
+  }
+
+  #[test]
+  fn position_flags_and_assignables() {
+    let old = config();
+    let mut new = config();
+    new.positions[1].ranked = true;
+    let pilot = new.assignable.remove("Pilot").unwrap();
+    new.assignable.insert("Aviator".to_owned(), pilot);
+    new.assignable.insert("Engineer".to_owned(), RoleId(350000000000000003));
+    new.assignable.remove("Medic");
+
+    let diff = ConfigDiff::new(&old, &new);
+    assert_eq!(diff.position_flags_changed, vec![("Visitor".to_owned(), "ranked", false, true)]);
+    assert_eq!(diff.assignables_renamed, vec![("Pilot".to_owned(), "Aviator".to_owned())]);
+    assert_eq!(diff.assignables_added, vec![("Engineer".to_owned(), RoleId(350000000000000003))]);
+    assert_eq!(diff.assignables_removed, vec![("Medic".to_owned(), RoleId(350000000000000001))]);
+
+    let members = vec![vec![RoleId(350000000000000001)]];
+    let warnings = diff.warnings(&members);
+    assert!(warnings.contains(&"Removed assignable `Medic` is still held by 1 member(s)".to_owned()));
+    assert!(warnings.contains(&"Members with position `Visitor` will need a rank they may not have".to_owned()));
+  }
+
+  #[test]
+  fn menu_greeting_and_other_fields() {
+    let old = config();
+    let mut new = config();
+    new.role_menu_positions.pop();
+    new.greeting.push("Have fun.".to_owned());
+    new.shutdown_timeout += 1;
+    new.owners.clear();
+
+    let diff = ConfigDiff::new(&old, &new);
+    assert_eq!(diff.menu_emojis_changed.len(), 1);
+    assert_eq!(diff.menu_emojis_changed[0].0, "Visitor");
+    assert!(diff.menu_emojis_changed[0].2.is_none());
+    assert!(diff.greeting_changed);
+    assert_eq!(diff.other_fields, vec!["owners", "shutdown_timeout"]);
+
+    let text = diff.to_string();
+    assert!(text.contains("~ `owners` changed (requires restart)"));
+    assert!(text.contains("~ `shutdown_timeout` changed\n"));
+  }
+}
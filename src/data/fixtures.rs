@@ -0,0 +1,27 @@
+// Configs and persist files written by older versions, shared by the tests
+
+use serde_json::Value;
+
+use super::config::Config;
+use super::format::Format;
+use super::migrate::{self, Kind};
+
+const CONFIG_0_3_0: &str = include_str!("../../tests/fixtures/config-0.3.0.json");
+const PERSIST_0_3_0: &str = include_str!("../../tests/fixtures/persist-0.3.0.json");
+
+/// The 0.3.0 config as it was written, before migrating
+pub fn config_0_3_0() -> Value {
+  Format::Json.parse(CONFIG_0_3_0).unwrap()
+}
+
+/// The 0.3.0 persist file as it was written, before migrating
+pub fn persist_0_3_0() -> Value {
+  Format::Json.parse(PERSIST_0_3_0).unwrap()
+}
+
+/// The 0.3.0 config migrated to the current version
+pub fn config() -> Config {
+  let mut value = config_0_3_0();
+  migrate::migrate(Kind::Config, &mut value).unwrap();
+  serde_json::from_value(value).unwrap()
+}
@@ -0,0 +1,147 @@
+use std::fmt;
+use std::path::Path;
+
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::error::Error;
+
+/// A file format that config and persist files may be written in.
+///
+/// Every format is read into and written from an intermediate JSON value, so types that
+/// serialize as newtypes (like serenity's IDs) are written as bare numbers in every format
+/// and read back the same way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+  Json,
+  Ron,
+  Toml
+}
+
+impl Format {
+  pub const ALL: [Format; 3] = [Format::Json, Format::Ron, Format::Toml];
+
+  pub fn from_name(name: &str) -> Option<Format> {
+    match name.to_lowercase().as_str() {
+      "json" => Some(Format::Json),
+      "ron" => Some(Format::Ron),
+      "toml" => Some(Format::Toml),
+      _ => None
+    }
+  }
+
+  pub fn from_path(path: impl AsRef<Path>) -> Option<Format> {
+    Format::from_name(path.as_ref().extension()?.to_str()?)
+  }
+
+  pub fn extension(self) -> &'static str {
+    match self {
+      Format::Json => "json",
+      Format::Ron => "ron",
+      Format::Toml => "toml"
+    }
+  }
+
+  /// Parses text in this format into a generic value
+  pub fn parse(self, text: &str) -> Result<Value, Error> {
+    match self {
+      Format::Json => serde_json::from_str(text).map_err(|err| self.error(err)),
+      Format::Ron => ron::from_str(text).map_err(|err| self.error(err)),
+      Format::Toml => toml::from_str(text).map_err(|err| self.error(err))
+    }
+  }
+
+  /// Renders a generic value as text in this format
+  pub fn render(self, value: &Value) -> Result<String, Error> {
+    match self {
+      Format::Json => serde_json::to_string_pretty(value).map_err(|err| self.error(err)),
+      Format::Ron => {
+        let pretty = ron::ser::PrettyConfig::new();
+        ron::ser::to_string_pretty(value, pretty).map_err(|err| self.error(err))
+      },
+      Format::Toml => {
+        // TOML has no null, so leave out anything that isn't set
+        let mut value = value.clone();
+        strip_nulls(&mut value);
+        toml::Value::try_from(value)
+          .and_then(|value| toml::to_string_pretty(&value))
+          .map_err(|err| self.error(err))
+      }
+    }
+  }
+
+  pub fn to_string<T: Serialize>(self, value: &T) -> Result<String, Error> {
+    self.render(&serde_json::to_value(value)?)
+  }
+
+  fn error(self, err: impl fmt::Display) -> Error {
+    Error::Invalid(format!("{} error: {}", self, err))
+  }
+}
+
+impl fmt::Display for Format {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(match self {
+      Format::Json => "JSON",
+      Format::Ron => "RON",
+      Format::Toml => "TOML"
+    })
+  }
+}
+
+fn strip_nulls(value: &mut Value) {
+  match value {
+    Value::Object(object) => {
+      object.retain(|_, value| !value.is_null());
+      object.values_mut().for_each(strip_nulls);
+    },
+    Value::Array(array) => array.iter_mut().for_each(strip_nulls),
+    _ => ()
+  };
+}
+
+#[cfg(test)]
+mod tests {
+  use serenity::model::{
+    channel::ReactionType,
+    id::{ChannelId, EmojiId, MessageId}
+  };
+
+  use super::*;
+  use crate::data::config::Config;
+  use crate::data::fixtures;
+
+  fn config() -> Config {
+    let mut config = fixtures::config();
+    config.offline_notice = Some((ChannelId(360000000000000003), "Going offline".to_owned()));
+    config
+  }
+
+  fn round_trip(format: Format) {
+    let config = config();
+    let text = format.to_string(&config).unwrap();
+    let read: Config = serde_json::from_value(format.parse(&text).unwrap()).unwrap();
+    assert_eq!(read, config, "{} did not round-trip:\n{}", format, text);
+
+    let custom = ReactionType::Custom { animated: false, id: EmojiId(380000000000000001), name: Some("rifle".to_owned()) };
+    assert_eq!(read.role_menu_positions[0].emoji, custom);
+    assert_eq!(read.role_menu_positions[1].emoji, ReactionType::Unicode("\u{2708}".to_owned()));
+    assert_eq!(read.role_menu, (ChannelId(360000000000000001), MessageId(370000000000000001)));
+    assert_eq!(read.offline_notice, Some((ChannelId(360000000000000003), "Going offline".to_owned())));
+  }
+
+  #[test]
+  fn json_round_trips() {
+    round_trip(Format::Json);
+  }
+
+  #[test]
+  fn ron_round_trips() {
+    round_trip(Format::Ron);
+  }
+
+  #[test]
+  fn toml_round_trips() {
+    round_trip(Format::Toml);
+  }
+}
@@ -0,0 +1,310 @@
+use serde_json::{Map, Value};
+
+use crate::error::Error;
+
+/// The field used to match up list entries between layers
+const KEY_FIELD: &str = "name";
+/// Set to `true` on an entry in an override list to remove the entry with that name from the base
+const REMOVE_FIELD: &str = "remove";
+
+/// Merges an override layer into a base layer.
+///
+/// Maps are merged recursively. Lists of entries that all have a `name` are merged
+/// entry by entry, with entries that only exist in the override appended to the end,
+/// and entries marked `"remove": true` removing the base entry of the same name.
+/// Any other value in the override replaces the value in the base.
+pub fn merge(base: &mut Value, over: Value) {
+  match (base, over) {
+    (Value::Object(base), Value::Object(over)) => {
+      for (key, over) in over {
+        match base.get_mut(&key) {
+          Some(base) => merge(base, over),
+          None => { base.insert(key, over); }
+        };
+      };
+    },
+    (Value::Array(base), Value::Array(over)) if is_keyed(base) && is_keyed(&over) => {
+      for over in over {
+        let position = base.iter().position(|base| key_of(base) == key_of(&over));
+        match (position, is_removal(&over)) {
+          (Some(position), true) => { base.remove(position); },
+          (None, true) => (),
+          (Some(position), false) => merge(&mut base[position], over),
+          (None, false) => base.push(over)
+        };
+      };
+    },
+    (base, over) => *base = over
+  };
+}
+
+/// Writes an edited value back into the two layers it was merged from, so that merging them
+/// again gives the edited value.
+///
+/// The base is meant to be shared between deployments, so only what it already defines is
+/// changed there: values it alone defines, and the order of its own named entries. Values the
+/// override defines are changed in the override, and new keys and named entries are added to it.
+/// Keys and named entries that were removed are removed from both layers.
+///
+/// Fails if the edit can't be written without adding to the base, which happens when an entry
+/// only the override defines is moved before one of the base's: merging always puts them last.
+pub fn unmerge(base: &mut Value, over: &mut Value, new: Value) -> Result<(), Error> {
+  let mut merged = base.clone();
+  merge(&mut merged, over.clone());
+  if merged == new { return Ok(()) };
+
+  match (&mut *base, &mut *over, new.clone()) {
+    (Value::Object(base), Value::Object(over), Value::Object(new)) => {
+      base.retain(|key, _| new.contains_key(key));
+      over.retain(|key, _| new.contains_key(key));
+      for (key, new) in new {
+        unmerge_key(base, over, &key, new)?;
+      };
+    },
+    (Value::Array(base), Value::Array(over), Value::Array(new)) if is_keyed(base) && is_keyed(over) && is_keyed(&new) => {
+      unmerge_list(base, over, new)?;
+    },
+    (_, over, new) => *over = new
+  };
+
+  let mut merged = base.clone();
+  merge(&mut merged, over.clone());
+  match merged == new {
+    true => Ok(()),
+    false => Err(Error::Custom("entries only the override file defines can't be moved before the others"))
+  }
+}
+
+/// Writes the edited value of one key of a map back into the layers, see `unmerge`
+pub fn unmerge_key(base: &mut Map<String, Value>, over: &mut Map<String, Value>, key: &str, new: Value) -> Result<(), Error> {
+  match (base.get_mut(key), over.get_mut(key)) {
+    (Some(base), Some(over)) => unmerge(base, over, new)?,
+    (_, Some(over)) => *over = new,
+    // Maps and named lists only the base has may still get new entries, which go to the override
+    (Some(base), None) => match empty_like(base, &new) {
+      Some(mut over_value) => {
+        unmerge(base, &mut over_value, new)?;
+        if !is_empty(&over_value) {
+          over.insert(key.to_owned(), over_value);
+        };
+      },
+      None => *base = new
+    },
+    (None, None) => { over.insert(key.to_owned(), new); }
+  };
+
+  Ok(())
+}
+
+/// An empty map or named list to stand in for the override of a base value, if it is one
+fn empty_like(base: &Value, new: &Value) -> Option<Value> {
+  match (base, new) {
+    (Value::Object(_), Value::Object(_)) => Some(Value::Object(Map::new())),
+    (Value::Array(base), Value::Array(new)) if is_keyed(base) && is_keyed(new) => Some(Value::Array(Vec::new())),
+    _ => None
+  }
+}
+
+fn is_empty(value: &Value) -> bool {
+  match value {
+    Value::Object(map) => map.is_empty(),
+    Value::Array(list) => list.is_empty(),
+    _ => false
+  }
+}
+
+fn unmerge_list(base: &mut Vec<Value>, over: &mut Vec<Value>, new: Vec<Value>) -> Result<(), Error> {
+  let (mut removals, mut old_over): (Vec<Value>, Vec<Value>) = over.drain(..).partition(is_removal);
+  let mut old_base = std::mem::take(base);
+  let mut new_over = Vec::new();
+
+  for entry in new {
+    let key = key_of(&entry).unwrap_or_default().to_owned();
+    // An entry added back after the override removed it takes the removed one's place
+    take(&mut removals, &key);
+    match (take(&mut old_base, &key), take(&mut old_over, &key)) {
+      (Some(mut base_entry), Some(mut over_entry)) => {
+        unmerge(&mut base_entry, &mut over_entry, entry)?;
+        base.push(base_entry);
+        new_over.push(over_entry);
+      },
+      (Some(_), None) => base.push(entry),
+      (None, _) => new_over.push(entry)
+    };
+  };
+
+  // Entries the override still removes stay in both layers, they never show up after merging anyway
+  base.extend(old_base.into_iter().filter(|entry| {
+    removals.iter().any(|removal| key_of(removal) == key_of(entry))
+  }));
+  over.extend(new_over);
+  over.extend(removals);
+  Ok(())
+}
+
+/// Takes the entry with the given name out of a list
+fn take(list: &mut Vec<Value>, key: &str) -> Option<Value> {
+  let position = list.iter().position(|entry| key_of(entry) == Some(key))?;
+  Some(list.remove(position))
+}
+
+fn is_keyed(list: &[Value]) -> bool {
+  list.iter().all(|entry| key_of(entry).is_some())
+}
+
+pub fn key_of(entry: &Value) -> Option<&str> {
+  entry.get(KEY_FIELD)?.as_str()
+}
+
+fn is_removal(entry: &Value) -> bool {
+  entry.get(REMOVE_FIELD).and_then(Value::as_bool).unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  fn merged(mut base: Value, over: Value) -> Value {
+    merge(&mut base, over);
+    base
+  }
+
+  #[test]
+  fn maps_merge_deeply() {
+    let base = json!({ "a": 1, "b": { "c": 2, "d": 3 } });
+    let over = json!({ "b": { "d": 4, "e": 5 }, "f": 6 });
+    assert_eq!(merged(base, over), json!({ "a": 1, "b": { "c": 2, "d": 4, "e": 5 }, "f": 6 }));
+  }
+
+  #[test]
+  fn scalars_and_plain_lists_are_replaced() {
+    let base = json!({ "a": 1, "b": "text", "c": [1, 2, 3], "d": { "e": 1 } });
+    let over = json!({ "a": null, "b": "other", "c": [4], "d": 7 });
+    assert_eq!(merged(base, over), json!({ "a": null, "b": "other", "c": [4], "d": 7 }));
+  }
+
+  #[test]
+  fn keyed_lists_add_and_update_by_name() {
+    let base = json!([{ "name": "x", "role": 1 }, { "name": "y", "role": 2 }]);
+    let over = json!([{ "name": "z", "role": 3 }, { "name": "x", "role": 4, "admin": true }]);
+    assert_eq!(merged(base, over), json!([
+      { "name": "x", "role": 4, "admin": true },
+      { "name": "y", "role": 2 },
+      { "name": "z", "role": 3 }
+    ]));
+  }
+
+  #[test]
+  fn keyed_lists_remove_marked_entries() {
+    let base = json!([{ "name": "x", "role": 1 }, { "name": "y", "role": 2 }]);
+    let over = json!([{ "name": "x", "remove": true }, { "name": "w", "remove": true }]);
+    assert_eq!(merged(base, over), json!([{ "name": "y", "role": 2 }]));
+  }
+
+  /// Unmerges `new` into the layers and checks that merging them again gives `new`
+  fn unmerged(mut base: Value, mut over: Value, new: Value) -> (Value, Value) {
+    unmerge(&mut base, &mut over, new.clone()).unwrap();
+    assert_eq!(merged(base.clone(), over.clone()), new);
+    (base, over)
+  }
+
+  #[test]
+  fn unmerge_leaves_unchanged_layers_alone() {
+    let base = json!({ "a": [{ "name": "x", "role": 1 }, { "name": "y", "role": 2 }], "b": 1 });
+    let over = json!({ "a": [{ "name": "x", "role": 3 }, { "name": "z", "remove": true }] });
+    let new = merged(base.clone(), over.clone());
+    assert_eq!(unmerged(base.clone(), over.clone(), new), (base, over));
+  }
+
+  #[test]
+  fn unmerge_writes_to_the_layer_that_defines_a_value() {
+    let base = json!({ "a": 1, "b": 2, "m": { "x": 1, "y": 2 } });
+    let over = json!({ "b": 3, "m": { "y": 4 } });
+    let new = json!({ "a": 5, "b": 6, "m": { "x": 7, "y": 8 }, "c": 9 });
+    assert_eq!(unmerged(base, over, new), (
+      json!({ "a": 5, "b": 2, "m": { "x": 7, "y": 2 } }),
+      json!({ "b": 6, "m": { "y": 8 }, "c": 9 })
+    ));
+  }
+
+  #[test]
+  fn unmerge_removes_keys_from_both_layers() {
+    let base = json!({ "m": { "x": 1, "y": 2, "z": 3 } });
+    let over = json!({ "m": { "y": 4 } });
+    let new = json!({ "m": { "x": 1 } });
+    assert_eq!(unmerged(base, over, new), (json!({ "m": { "x": 1 } }), json!({ "m": {} })));
+  }
+
+  #[test]
+  fn unmerge_removes_entries_from_the_layers_that_have_them() {
+    let base = json!([{ "name": "x", "role": 1 }, { "name": "y", "role": 2 }]);
+    let over = json!([{ "name": "y", "role": 3 }, { "name": "z", "role": 4 }]);
+    let (base, over) = unmerged(base, over, json!([{ "name": "x", "role": 1 }]));
+    assert_eq!(base, json!([{ "name": "x", "role": 1 }]));
+    assert_eq!(over, json!([]));
+  }
+
+  #[test]
+  fn unmerge_updates_and_adds_entries() {
+    let base = json!([{ "name": "x", "role": 1, "admin": false }]);
+    let over = json!([{ "name": "x", "role": 2 }, { "name": "z", "role": 3 }]);
+    let new = json!([
+      { "name": "x", "role": 4, "admin": true },
+      { "name": "z", "role": 5 },
+      { "name": "w", "role": 6 }
+    ]);
+    let (base, over) = unmerged(base, over, new);
+    assert_eq!(base, json!([{ "name": "x", "role": 1, "admin": true }]));
+    assert_eq!(over, json!([{ "name": "x", "role": 4 }, { "name": "z", "role": 5 }, { "name": "w", "role": 6 }]));
+  }
+
+  #[test]
+  fn unmerge_adds_to_the_override_even_if_only_the_base_has_the_list() {
+    let base = json!({ "a": [{ "name": "x", "role": 1 }], "m": { "x": 1 }, "b": [1] });
+    let new = json!({ "a": [{ "name": "x", "role": 2 }, { "name": "y", "role": 3 }], "m": { "x": 1, "y": 2 }, "b": [1, 2] });
+    assert_eq!(unmerged(base, json!({}), new), (
+      json!({ "a": [{ "name": "x", "role": 2 }], "m": { "x": 1 }, "b": [1, 2] }),
+      json!({ "a": [{ "name": "y", "role": 3 }], "m": { "y": 2 } })
+    ));
+  }
+
+  #[test]
+  fn unmerge_reorders_in_the_base() {
+    let base = json!([{ "name": "x", "role": 1 }, { "name": "y", "role": 2 }]);
+    let over = json!([{ "name": "x", "role": 3 }, { "name": "z", "role": 4 }]);
+
+    // Moving a base entry only reorders the base
+    let new = json!([{ "name": "y", "role": 2 }, { "name": "x", "role": 3 }, { "name": "z", "role": 4 }]);
+    let (moved_base, moved_over) = unmerged(base.clone(), over.clone(), new);
+    assert_eq!(moved_base, json!([{ "name": "y", "role": 2 }, { "name": "x", "role": 1 }]));
+    assert_eq!(moved_over, over);
+
+    // Override-only entries always come after the base's, so they can't be moved before them
+    let new = json!([{ "name": "z", "role": 4 }, { "name": "x", "role": 3 }, { "name": "y", "role": 2 }]);
+    let (mut moved_base, mut moved_over) = (base, over);
+    assert!(unmerge(&mut moved_base, &mut moved_over, new).is_err());
+  }
+
+  #[test]
+  fn unmerge_keeps_removals_unless_the_entry_is_added_back() {
+    let base = json!([{ "name": "x", "role": 1 }, { "name": "y", "role": 2 }]);
+    let over = json!([{ "name": "x", "remove": true }]);
+
+    let (kept_base, kept_over) = unmerged(base.clone(), over.clone(), json!([]));
+    assert_eq!(kept_base, json!([{ "name": "x", "role": 1 }]));
+    assert_eq!(kept_over, over);
+
+    let new = json!([{ "name": "y", "role": 2 }, { "name": "x", "role": 5 }]);
+    let (added_base, added_over) = unmerged(base, over, new);
+    assert_eq!(added_base, json!([{ "name": "y", "role": 2 }, { "name": "x", "role": 5 }]));
+    assert_eq!(added_over, json!([]));
+  }
+
+  #[test]
+  fn lists_that_are_not_all_keyed_are_replaced() {
+    let base = json!([{ "name": "x" }, { "name": "y" }]);
+    let over = json!([{ "name": "z" }, { "id": 1 }]);
+    assert_eq!(merged(base, over), json!([{ "name": "z" }, { "id": 1 }]));
+  }
+}
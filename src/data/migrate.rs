@@ -0,0 +1,122 @@
+use std::fs;
+use std::path::Path;
+
+use serde_json::Value;
+
+use crate::error::Error;
+use super::format::Format;
+
+/// A migration upgrades a file from one version of its layout to the next
+type Migration = fn(&mut Value) -> Result<(), Error>;
+
+/// Config migrations, the migration at index `n` upgrades version `n` to version `n + 1`.
+/// Version 0 is the unversioned layout used up to 0.3.0.
+const CONFIG_MIGRATIONS: &[Migration] = &[stamp_version];
+/// Persist migrations, the migration at index `n` upgrades version `n` to version `n + 1`.
+/// Version 0 is the unversioned layout used up to 0.3.0.
+const PERSIST_MIGRATIONS: &[Migration] = &[stamp_version];
+
+pub const CONFIG_VERSION: u32 = CONFIG_MIGRATIONS.len() as u32;
+pub const PERSIST_VERSION: u32 = PERSIST_MIGRATIONS.len() as u32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+  Config,
+  Persist
+}
+
+impl Kind {
+  fn migrations(self) -> &'static [Migration] {
+    match self {
+      Kind::Config => CONFIG_MIGRATIONS,
+      Kind::Persist => PERSIST_MIGRATIONS
+    }
+  }
+}
+
+/// Upgrades a value to the current layout, returning the version it was upgraded from, if any
+pub fn migrate(kind: Kind, value: &mut Value) -> Result<Option<u32>, Error> {
+  let migrations = kind.migrations();
+  let current = migrations.len() as u32;
+  let version = match value.get("version") {
+    Some(version) => version.as_u64()
+      .ok_or(Error::Custom("the `version` field must be a number"))? as u32,
+    None => 0
+  };
+
+  if !value.is_object() {
+    return Err(Error::Custom("expected a map at the top level"));
+  } else if version > current {
+    let message = format!("file has version {}, but this build only understands up to version {}", version, current);
+    return Err(Error::Invalid(message));
+  } else if version == current {
+    return Ok(None);
+  };
+
+  for migration in migrations[version as usize..].iter() {
+    migration(value)?;
+  };
+
+  value["version"] = Value::from(current);
+  Ok(Some(version))
+}
+
+/// Upgrades a value read from a file to the current layout. If it was upgraded, the original
+/// file is copied to `<file>.v<version>.bak` and then replaced with the upgraded value.
+pub fn migrate_file(kind: Kind, path: &Path, format: Format, value: &mut Value) -> Result<(), Error> {
+  if let Some(version) = migrate(kind, value)? {
+    let file_name = path.file_name().map_or("file".into(), |name| name.to_string_lossy());
+    let backup_path = path.with_file_name(format!("{}.v{}.bak", file_name, version));
+    fs::copy(path, &backup_path)?;
+    super::write_atomic(path, &format.render(value)?)?;
+    log!(Info, "Upgraded {} from version {} to {}, the original was kept as {}",
+      path.display(), version, kind.migrations().len(), backup_path.display());
+  };
+
+  Ok(())
+}
+
+/// 0 -> 1: The layout is unchanged, files only gain a `version` field
+fn stamp_version(_: &mut Value) -> Result<(), Error> {
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::data::config::Config;
+  use crate::data::fixtures;
+  use crate::data::persist::Persist;
+
+  #[test]
+  fn config_0_3_0_migrates() {
+    let mut value = fixtures::config_0_3_0();
+    assert_eq!(migrate(Kind::Config, &mut value).unwrap(), Some(0));
+    let config: Config = serde_json::from_value(value).unwrap();
+    assert_eq!(config.version, CONFIG_VERSION);
+    assert_eq!(config.ranks.len(), 3);
+    assert_eq!(config.token.as_deref(), Some("not-a-real-token"));
+    config.validate().unwrap();
+  }
+
+  #[test]
+  fn persist_0_3_0_migrates() {
+    let mut value = fixtures::persist_0_3_0();
+    assert_eq!(migrate(Kind::Persist, &mut value).unwrap(), Some(0));
+    let persist: Persist = serde_json::from_value(value).unwrap();
+    assert_eq!(persist.version, PERSIST_VERSION);
+    assert_eq!(persist.greeted_users.len(), 2);
+  }
+
+  #[test]
+  fn current_version_is_untouched() {
+    let mut value = serde_json::json!({ "version": PERSIST_VERSION, "greeted_users": [] });
+    assert_eq!(migrate(Kind::Persist, &mut value).unwrap(), None);
+  }
+
+  #[test]
+  fn newer_version_is_rejected() {
+    let mut value = serde_json::json!({ "version": PERSIST_VERSION + 1, "greeted_users": [] });
+    assert!(migrate(Kind::Persist, &mut value).is_err());
+  }
+}
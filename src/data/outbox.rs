@@ -0,0 +1,57 @@
+use std::fmt;
+
+use serenity::model::{
+  channel::ReactionType,
+  id::{ChannelId, GuildId, MessageId, RoleId, UserId}
+};
+
+/// A Discord operation that failed and is waiting to be retried
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OutboxItem {
+  pub id: u64,
+  pub operation: Operation,
+  /// How many times the operation has been tried
+  pub attempts: u32,
+  /// Unix time (in seconds) of the next attempt
+  pub next_attempt: u64,
+  pub last_error: Option<String>,
+  /// Set once the operation has failed in a way that retrying won't fix, or has run out of attempts
+  #[serde(default)]
+  pub stuck: bool
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Operation {
+  /// Adds and removes roles relative to whatever roles the member has when it is retried
+  EditRoles {
+    guild_id: GuildId,
+    user_id: UserId,
+    add: Vec<RoleId>,
+    remove: Vec<RoleId>
+  },
+  DeleteReaction {
+    channel_id: ChannelId,
+    message_id: MessageId,
+    user_id: UserId,
+    emoji: ReactionType
+  },
+  SendMessage {
+    channel_id: ChannelId,
+    content: String
+  }
+}
+
+impl fmt::Display for Operation {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Operation::EditRoles { user_id, add, remove, .. } => {
+        write!(f, "edit roles of {}", user_id)?;
+        for role in add { write!(f, " +{}", role)? };
+        for role in remove { write!(f, " -{}", role)? };
+        Ok(())
+      },
+      Operation::DeleteReaction { user_id, emoji, .. } => write!(f, "delete {} reaction of {}", emoji, user_id),
+      Operation::SendMessage { channel_id, .. } => write!(f, "send message in {}", channel_id)
+    }
+  }
+}
@@ -1,24 +1,237 @@
 use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::ops::{Deref, DerefMut};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
-use singlefile::serde_multi::formats::json::Json;
-use singlefile::BackendWritable;
 use serenity::{
   prelude::{TypeMapKey, RwLock},
   model::id::UserId
 };
 
-pub type PersistFile = BackendWritable<Persist, Json>;
+use crate::error::Error;
+use super::backup;
+use super::format::Format;
+use super::migrate::{self, Kind, PERSIST_VERSION};
+use super::outbox::OutboxItem;
+use super::recommend::Recommendation;
+use super::sqlite;
+use super::store::Store;
+
+/// The persist file used when none is given, other formats are used instead if present
+pub const PERSIST_PATH: &str = "persist.json";
+
+/// Persistent state, read from and written back to a file
+#[derive(Debug)]
+pub struct PersistFile {
+  persist: Persist,
+  path: PathBuf,
+  format: Format,
+  backup_limit: usize
+}
+
+impl PersistFile {
+  /// Creates a persist file in memory, without touching the disk
+  pub fn new(path: impl AsRef<Path>, format: Format, persist: Persist) -> PersistFile {
+    PersistFile { persist, path: path.as_ref().to_owned(), format, backup_limit: 0 }
+  }
+
+  /// Opens an existing persist file, upgrading it on disk if it uses an older layout
+  pub fn open(path: impl AsRef<Path>, format: Option<Format>) -> Result<PersistFile, Error> {
+    let path = path.as_ref().to_owned();
+    let format = format.or_else(|| Format::from_path(&path)).unwrap_or(Format::Json);
+    let mut value = format.parse(&fs::read_to_string(&path)?)?;
+    migrate::migrate_file(Kind::Persist, &path, format, &mut value)?;
+    let persist = serde_json::from_value(value)?;
+    Ok(PersistFile { persist, path, format, backup_limit: 0 })
+  }
+
+  /// Reads a persist file without modifying it, upgrading its contents in memory if needed
+  pub fn load(path: impl AsRef<Path>, format: Option<Format>) -> Result<Persist, Error> {
+    let path = path.as_ref();
+    let format = format.or_else(|| Format::from_path(path)).unwrap_or(Format::Json);
+    let mut value = format.parse(&fs::read_to_string(path)?)?;
+    migrate::migrate(Kind::Persist, &mut value)?;
+    Ok(serde_json::from_value(value)?)
+  }
+
+  /// Opens a persist file, creating it with default contents if it doesn't exist,
+  /// or recovering it from a backup if it is corrupt
+  pub fn create_or_default(path: impl AsRef<Path>, format: Option<Format>) -> Result<PersistFile, Error> {
+    match PersistFile::open(&path, format) {
+      Err(Error::Io(err)) if err.kind() == io::ErrorKind::NotFound => {
+        let format = format.or_else(|| Format::from_path(&path)).unwrap_or(Format::Json);
+        let persist_file = PersistFile::new(path, format, Persist::default());
+        persist_file.commit()?;
+        Ok(persist_file)
+      },
+      Err(Error::Io(err)) => Err(err.into()),
+      Err(err) => PersistFile::recover(path.as_ref(), format, err),
+      result => result
+    }
+  }
+
+  /// Replaces a corrupt persist file with the newest backup that loads, keeping the corrupt
+  /// file as `<file>.corrupt`. Files written by a newer build are left alone.
+  fn recover(path: &Path, format: Option<Format>, err: Error) -> Result<PersistFile, Error> {
+    let format = format.or_else(|| Format::from_path(path)).unwrap_or(Format::Json);
+    let version = fs::read_to_string(path).ok()
+      .and_then(|contents| format.parse(&contents).ok())
+      .and_then(|value| value.get("version").and_then(|version| version.as_u64()));
+    if version.is_some_and(|version| version > PERSIST_VERSION as u64) {
+      return Err(err);
+    };
+
+    log!(Error, "Error: Persist file {} is corrupt: {:?}", path.display(), err);
+    for backup_path in backup::list(path)? {
+      match PersistFile::load(&backup_path, Some(format)) {
+        Ok(persist) => {
+          let file_name = path.file_name().map_or("persist".into(), |name| name.to_string_lossy());
+          let corrupt_path = path.with_file_name(format!("{}.corrupt", file_name));
+          fs::rename(path, &corrupt_path)?;
+
+          let persist_file = PersistFile::new(path, format, persist);
+          persist_file.commit()?;
+          log!(
+            Error, "Error: Recovered persist from backup {}, the corrupt file was moved to {}",
+            backup_path.display(), corrupt_path.display()
+          );
+          return Ok(persist_file);
+        },
+        Err(backup_err) => log!(Warn, "Backup {} doesn't load either: {:?}", backup_path.display(), backup_err)
+      };
+    };
+
+    log!(Error, "Error: No backup of {} could be loaded", path.display());
+    Err(err)
+  }
+}
+
+impl Store for PersistFile {
+  fn path(&self) -> &Path {
+    &self.path
+  }
+
+  fn backend(&self) -> &'static str {
+    "file"
+  }
+
+  fn should_greet(&self, user_id: UserId) -> Result<bool, Error> {
+    Ok(self.persist.should_greet(user_id))
+  }
+
+  fn register_greeted(&mut self, user_id: UserId) -> Result<bool, Error> {
+    Ok(self.persist.register_greeted(user_id))
+  }
+
+  fn set_greeted(&mut self, user_ids: HashSet<UserId>) -> Result<(), Error> {
+    self.persist.greeted_users = user_ids;
+    Ok(())
+  }
+
+  fn outbox(&self) -> Result<Vec<OutboxItem>, Error> {
+    Ok(self.persist.outbox.clone())
+  }
+
+  fn set_outbox(&mut self, items: Vec<OutboxItem>) -> Result<(), Error> {
+    self.persist.outbox = items;
+    Ok(())
+  }
+
+  fn recommendations(&self) -> Result<Vec<Recommendation>, Error> {
+    Ok(self.persist.recommendations.clone())
+  }
+
+  fn set_recommendations(&mut self, recommendations: Vec<Recommendation>) -> Result<(), Error> {
+    self.persist.recommendations = recommendations;
+    Ok(())
+  }
+
+  fn snapshot(&self) -> Result<Persist, Error> {
+    Ok(self.persist.clone())
+  }
+
+  fn replace(&mut self, persist: Persist) -> Result<(), Error> {
+    self.persist = persist;
+    Ok(())
+  }
+
+  /// Re-reads the file, keeping the current state if it fails to load
+  fn refresh(&mut self) -> Result<bool, Error> {
+    let persist = PersistFile::load(&self.path, Some(self.format))?;
+    let changed = persist != self.persist;
+    self.persist = persist;
+    Ok(changed)
+  }
+
+  /// Writes the current state to the file atomically, first copying the previous version into
+  /// the backup directory if backups are enabled and the newest one is over an hour old
+  fn commit(&self) -> Result<(), Error> {
+    let contents = self.format.to_string(&self.persist)?;
+    if self.backup_limit > 0 && self.path.exists() && backup::is_due(&self.path)? {
+      backup::create(&self.path, self.backup_limit)?;
+    };
+
+    super::replace_atomic(&self.path, &contents)
+  }
+
+  fn set_backup_limit(&mut self, limit: usize) {
+    self.backup_limit = limit;
+  }
+
+  fn backup(&self) -> Result<PathBuf, Error> {
+    let backup_path = backup::new_backup_path(&self.path)?;
+    super::replace_atomic(&backup_path, &self.format.to_string(&self.persist)?)?;
+    if self.backup_limit > 0 {
+      backup::prune(&self.path, self.backup_limit)?;
+    };
+
+    Ok(backup_path)
+  }
+}
+
+impl Deref for PersistFile {
+  type Target = Persist;
+
+  fn deref(&self) -> &Persist {
+    &self.persist
+  }
+}
+
+impl DerefMut for PersistFile {
+  fn deref_mut(&mut self) -> &mut Persist {
+    &mut self.persist
+  }
+}
+
+/// Finds the default persist file or database, matching the config format when none exists yet
+pub fn default_path(config_format: Format) -> PathBuf {
+  Format::ALL.iter()
+    .map(|format| format.extension())
+    .chain(sqlite::EXTENSIONS.iter().copied())
+    .map(|extension| Path::new(PERSIST_PATH).with_extension(extension))
+    .find(|path| path.exists())
+    .unwrap_or_else(|| Path::new(PERSIST_PATH).with_extension(config_format.extension()))
+}
 
 pub struct PersistContainer;
 
 impl TypeMapKey for PersistContainer {
-  type Value = Arc<RwLock<PersistFile>>;
+  type Value = Arc<RwLock<Box<dyn Store>>>;
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Persist {
+  /// The version of the persist layout, used to upgrade older persist files
+  pub version: u32,
   pub greeted_users: HashSet<UserId>,
+  /// Failed Discord operations waiting to be retried
+  #[serde(default)]
+  pub outbox: Vec<OutboxItem>,
+  /// Rank recommendations, open or decided
+  #[serde(default)]
+  pub recommendations: Vec<Recommendation>
 }
 
 impl Persist {
@@ -34,7 +247,10 @@ impl Persist {
 impl Default for Persist {
   fn default() -> Persist {
     Persist {
-      greeted_users: HashSet::new()
+      version: PERSIST_VERSION,
+      greeted_users: HashSet::new(),
+      outbox: Vec::new(),
+      recommendations: Vec::new()
     }
   }
 }
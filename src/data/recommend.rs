@@ -0,0 +1,43 @@
+use std::collections::HashSet;
+use std::fmt;
+
+use serenity::model::id::{ChannelId, MessageId, UserId};
+
+/// A recommendation for a member to be given a rank, voted on by approvers
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Recommendation {
+  pub id: u64,
+  /// The member being recommended
+  pub user_id: UserId,
+  /// Name of the rank they are recommended for
+  pub rank: String,
+  pub reason: String,
+  pub recommender: UserId,
+  /// Unix time (in seconds) the recommendation was filed
+  pub filed: u64,
+  /// The channel and message id of the ballot in the review channel, once it has been posted
+  pub ballot: Option<(ChannelId, MessageId)>,
+  pub approvals: HashSet<UserId>,
+  pub denials: HashSet<UserId>,
+  pub status: Status
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Status {
+  Open,
+  Approved,
+  Denied,
+  /// Approved, but the rank change couldn't be made
+  Failed
+}
+
+impl fmt::Display for Status {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(match self {
+      Status::Open => "open",
+      Status::Approved => "approved",
+      Status::Denied => "denied",
+      Status::Failed => "failed"
+    })
+  }
+}
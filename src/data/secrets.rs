@@ -0,0 +1,199 @@
+use std::env;
+use std::fs;
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+use serde_json::Value;
+
+use crate::error::Error;
+use super::merge::key_of;
+
+pub const SECRETS_PATH: &str = "secrets.json";
+
+/// Environment variable that the bot token may be provided through
+pub const TOKEN_VAR: &str = "SENTINEL_TOKEN";
+
+/// Values that should never be kept in the shared config file
+#[derive(Debug, Default, Deserialize)]
+pub struct Secrets {
+  /// Token used to sign the bot in
+  #[serde(default)]
+  pub token: Option<String>
+}
+
+impl Secrets {
+  /// Loads the secrets file if it exists, refusing to read it if other users can access it
+  pub fn load() -> Result<Secrets, Error> {
+    match fs::read_to_string(SECRETS_PATH) {
+      Ok(contents) => {
+        check_private(SECRETS_PATH)?;
+        Ok(serde_json::from_str(&contents)?)
+      },
+      Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Secrets::default()),
+      Err(err) => Err(err.into())
+    }
+  }
+}
+
+/// Reads a file containing only a token, refusing to read it if other users can access it
+pub fn read_token_file(path: impl AsRef<Path>) -> Result<String, Error> {
+  check_private(&path)?;
+  Ok(fs::read_to_string(path)?.trim().to_owned())
+}
+
+/// Fails if the file at the given path can be read or written by its group or by other users
+pub fn check_private(path: impl AsRef<Path>) -> Result<(), Error> {
+  let path = path.as_ref();
+  let mode = fs::metadata(path)?.permissions().mode();
+  if mode & 0o077 != 0 {
+    let message = format!("{} must only be accessible by its owner (try `chmod 600`)", path.display());
+    return Err(Error::Invalid(message));
+  };
+
+  Ok(())
+}
+
+/// Whether the file at the given path can be read by any user
+pub fn is_world_readable(path: impl AsRef<Path>) -> Result<bool, Error> {
+  let mode = fs::metadata(path)?.permissions().mode();
+  Ok(mode & 0o004 != 0)
+}
+
+/// Looks up the value of a variable referenced as `${VAR}`
+type Lookup<'a> = &'a dyn Fn(&str) -> Option<String>;
+
+/// Replaces `${VAR}` in every string in a value with the contents of environment variable `VAR`,
+/// `$${` is left as a literal `${`
+pub fn interpolate(value: &mut Value) -> Result<(), Error> {
+  interpolate_with(value, &|name| env::var(name).ok())
+}
+
+fn interpolate_with(value: &mut Value, lookup: Lookup) -> Result<(), Error> {
+  match value {
+    Value::String(string) => *string = interpolate_str(string, lookup)?,
+    Value::Array(array) => for value in array.iter_mut() {
+      interpolate_with(value, lookup)?;
+    },
+    Value::Object(object) => for value in object.values_mut() {
+      interpolate_with(value, lookup)?;
+    },
+    _ => ()
+  };
+
+  Ok(())
+}
+
+/// Puts back the `${VAR}` references from a raw (uninterpolated) value wherever interpolating
+/// them still gives the same result, so that writing a value back to a config file keeps the
+/// references instead of writing out what they stood for
+pub fn restore_references(value: &mut Value, raw: &Value) {
+  restore_references_with(value, raw, &|name| env::var(name).ok())
+}
+
+fn restore_references_with(value: &mut Value, raw: &Value, lookup: Lookup) {
+  let mut interpolated = raw.clone();
+  if interpolate_with(&mut interpolated, lookup).is_ok() && interpolated == *value {
+    *value = raw.clone();
+    return;
+  };
+
+  match (value, raw) {
+    (Value::Object(object), Value::Object(raw)) => for (key, value) in object.iter_mut() {
+      if let Some(raw) = raw.get(key) {
+        restore_references_with(value, raw, lookup);
+      };
+    },
+    (Value::Array(array), Value::Array(raw)) => for (i, value) in array.iter_mut().enumerate() {
+      // Named entries may have moved, anything else is matched up by position
+      let raw = match key_of(value).map(str::to_owned) {
+        Some(key) => raw.iter().find(|raw| key_of(raw) == Some(&key)),
+        None => raw.get(i)
+      };
+
+      if let Some(raw) = raw {
+        restore_references_with(value, raw, lookup);
+      };
+    },
+    _ => ()
+  };
+}
+
+fn interpolate_str(string: &str, lookup: Lookup) -> Result<String, Error> {
+  let mut out = String::with_capacity(string.len());
+  let mut rest = string;
+  while let Some(start) = rest.find('$') {
+    out.push_str(&rest[..start]);
+    rest = &rest[start..];
+    if rest.starts_with("$${") {
+      out.push_str("${");
+      rest = &rest[3..];
+    } else if rest.starts_with("${") {
+      let end = rest.find('}')
+        .ok_or_else(|| Error::Invalid(format!("unterminated `${{` in {:?} (write `$${{` for a literal `${{`)", string)))?;
+      let name = &rest[2..end];
+      let var = lookup(name)
+        .ok_or_else(|| Error::Invalid(format!("environment variable `{}` is not set", name)))?;
+      out.push_str(&var);
+      rest = &rest[end + 1..];
+    } else {
+      out.push('$');
+      rest = &rest[1..];
+    };
+  };
+
+  out.push_str(rest);
+  Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Stands in for the environment, with only `SENTINEL_TEST` set
+  fn lookup(name: &str) -> Option<String> {
+    (name == "SENTINEL_TEST").then(|| "value".to_owned())
+  }
+
+  #[test]
+  fn variables_are_replaced() {
+    assert_eq!(interpolate_str("a ${SENTINEL_TEST} b", &lookup).unwrap(), "a value b");
+    assert_eq!(interpolate_str("${SENTINEL_TEST}${SENTINEL_TEST}", &lookup).unwrap(), "valuevalue");
+  }
+
+  #[test]
+  fn escapes_and_lone_dollars_are_kept() {
+    assert_eq!(interpolate_str("costs $5, or $$5", &lookup).unwrap(), "costs $5, or $$5");
+    assert_eq!(interpolate_str("literal $${NAME} here", &lookup).unwrap(), "literal ${NAME} here");
+    assert_eq!(interpolate_str("ends with $", &lookup).unwrap(), "ends with $");
+  }
+
+  #[test]
+  fn references_are_restored_where_unchanged() {
+    let raw = serde_json::json!({
+      "a": "${SENTINEL_TEST}",
+      "b": ["x ${SENTINEL_TEST}", "y ${SENTINEL_TEST}"],
+      "c": [{ "name": "n", "text": "${SENTINEL_TEST}" }, { "name": "m", "text": "${SENTINEL_TEST}" }]
+    });
+    let mut value = serde_json::json!({
+      "a": "value",
+      "b": ["x value", "y changed"],
+      "c": [{ "name": "m", "text": "value" }, { "name": "n", "text": "changed" }],
+      "d": "value"
+    });
+
+    restore_references_with(&mut value, &raw, &lookup);
+    assert_eq!(value, serde_json::json!({
+      "a": "${SENTINEL_TEST}",
+      "b": ["x ${SENTINEL_TEST}", "y changed"],
+      "c": [{ "name": "m", "text": "${SENTINEL_TEST}" }, { "name": "n", "text": "changed" }],
+      "d": "value"
+    }));
+  }
+
+  #[test]
+  fn unset_and_unterminated_fail() {
+    assert!(interpolate_str("${SENTINEL_TEST_UNSET}", &lookup).is_err());
+    assert!(interpolate_str("open ${ brace", &lookup).is_err());
+  }
+}
@@ -0,0 +1,269 @@
+use std::collections::HashSet;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rusqlite::{params, Connection, OptionalExtension, Transaction};
+use serde::de::DeserializeOwned;
+use serenity::model::id::UserId;
+
+use crate::error::Error;
+use super::backup;
+use super::migrate::PERSIST_VERSION;
+use super::outbox::OutboxItem;
+use super::persist::Persist;
+use super::recommend::Recommendation;
+use super::store::Store;
+
+/// Extensions that mark a persist path as an SQLite database
+pub const EXTENSIONS: &[&str] = &["db", "sqlite", "sqlite3"];
+
+/// Schema migrations, the statements at index `n` upgrade the schema from version `n` to `n + 1`.
+/// The schema version is kept in the database's `user_version`.
+const MIGRATIONS: &[&str] = &[
+  "CREATE TABLE greeted_users (
+    user_id INTEGER PRIMARY KEY NOT NULL,
+    greeted_at INTEGER NOT NULL
+  );",
+  "CREATE TABLE outbox (
+    id INTEGER PRIMARY KEY NOT NULL,
+    item TEXT NOT NULL
+  );",
+  "CREATE TABLE recommendations (
+    id INTEGER PRIMARY KEY NOT NULL,
+    recommendation TEXT NOT NULL
+  );"
+];
+
+/// Persistent state kept in an SQLite database, where every change is written as it is made
+#[derive(Debug)]
+pub struct SqliteStore {
+  connection: Mutex<Connection>,
+  path: PathBuf,
+  backup_limit: usize
+}
+
+impl SqliteStore {
+  /// Opens an existing database
+  pub fn open(path: impl AsRef<Path>) -> Result<SqliteStore, Error> {
+    if !path.as_ref().exists() {
+      return Err(io::Error::new(io::ErrorKind::NotFound, "database does not exist").into());
+    };
+
+    SqliteStore::create(path)
+  }
+
+  /// Opens a database, creating it if it doesn't exist
+  pub fn create(path: impl AsRef<Path>) -> Result<SqliteStore, Error> {
+    let path = path.as_ref().to_owned();
+    let mut connection = Connection::open(&path)?;
+    connection.pragma_update(None, "journal_mode", "WAL")?;
+    connection.pragma_update(None, "foreign_keys", true)?;
+    migrate_schema(&mut connection)?;
+    Ok(SqliteStore { connection: Mutex::new(connection), path, backup_limit: 0 })
+  }
+
+  fn with_transaction<T, F>(&self, f: F) -> Result<T, Error>
+  where F: FnOnce(&Transaction) -> Result<T, Error> {
+    let mut connection = self.connection.lock().unwrap();
+    let transaction = connection.transaction()?;
+    let value = f(&transaction)?;
+    transaction.commit()?;
+    Ok(value)
+  }
+
+  /// Reads rows that each hold a single JSON-encoded value
+  fn select_json<T: DeserializeOwned>(&self, query: &str) -> Result<Vec<T>, Error> {
+    let connection = self.connection.lock().unwrap();
+    let mut statement = connection.prepare(query)?;
+    let values = statement
+      .query_map([], |row| row.get::<_, String>(0))?
+      .collect::<Result<Vec<String>, rusqlite::Error>>()?;
+    values.iter()
+      .map(|value| serde_json::from_str(value).map_err(Error::from))
+      .collect()
+  }
+}
+
+impl Store for SqliteStore {
+  fn path(&self) -> &Path {
+    &self.path
+  }
+
+  fn backend(&self) -> &'static str {
+    "sqlite"
+  }
+
+  fn should_greet(&self, user_id: UserId) -> Result<bool, Error> {
+    let connection = self.connection.lock().unwrap();
+    let greeted = connection
+      .query_row("SELECT 1 FROM greeted_users WHERE user_id = ?1", params![user_id.0 as i64], |_| Ok(()))
+      .optional()?;
+    Ok(greeted.is_none())
+  }
+
+  fn register_greeted(&mut self, user_id: UserId) -> Result<bool, Error> {
+    let connection = self.connection.lock().unwrap();
+    let inserted = connection.execute(
+      "INSERT OR IGNORE INTO greeted_users (user_id, greeted_at) VALUES (?1, ?2)",
+      params![user_id.0 as i64, now()]
+    )?;
+    Ok(inserted > 0)
+  }
+
+  fn set_greeted(&mut self, user_ids: HashSet<UserId>) -> Result<(), Error> {
+    self.with_transaction(|transaction| {
+      transaction.execute("DELETE FROM greeted_users", [])?;
+      insert_greeted(transaction, &user_ids)
+    })
+  }
+
+  fn outbox(&self) -> Result<Vec<OutboxItem>, Error> {
+    self.select_json("SELECT item FROM outbox ORDER BY id")
+  }
+
+  fn set_outbox(&mut self, items: Vec<OutboxItem>) -> Result<(), Error> {
+    self.with_transaction(|transaction| {
+      transaction.execute("DELETE FROM outbox", [])?;
+      insert_outbox(transaction, &items)
+    })
+  }
+
+  fn recommendations(&self) -> Result<Vec<Recommendation>, Error> {
+    self.select_json("SELECT recommendation FROM recommendations ORDER BY id")
+  }
+
+  fn set_recommendations(&mut self, recommendations: Vec<Recommendation>) -> Result<(), Error> {
+    self.with_transaction(|transaction| {
+      transaction.execute("DELETE FROM recommendations", [])?;
+      insert_recommendations(transaction, &recommendations)
+    })
+  }
+
+  fn snapshot(&self) -> Result<Persist, Error> {
+    let outbox = self.outbox()?;
+    let recommendations = self.recommendations()?;
+    let connection = self.connection.lock().unwrap();
+    let mut statement = connection.prepare("SELECT user_id FROM greeted_users")?;
+    let greeted_users = statement
+      .query_map([], |row| row.get::<_, i64>(0))?
+      .map(|user_id| user_id.map(|user_id| UserId(user_id as u64)))
+      .collect::<Result<HashSet<UserId>, rusqlite::Error>>()?;
+    Ok(Persist { version: PERSIST_VERSION, greeted_users, outbox, recommendations })
+  }
+
+  fn replace(&mut self, persist: Persist) -> Result<(), Error> {
+    self.with_transaction(|transaction| {
+      transaction.execute("DELETE FROM greeted_users", [])?;
+      insert_greeted(transaction, &persist.greeted_users)?;
+      transaction.execute("DELETE FROM outbox", [])?;
+      insert_outbox(transaction, &persist.outbox)?;
+      transaction.execute("DELETE FROM recommendations", [])?;
+      insert_recommendations(transaction, &persist.recommendations)
+    })
+  }
+
+  fn refresh(&mut self) -> Result<bool, Error> {
+    // Every read goes to the database, so there is nothing to pick up
+    Ok(false)
+  }
+
+  fn commit(&self) -> Result<(), Error> {
+    // Every change is committed as it is made
+    Ok(())
+  }
+
+  fn set_backup_limit(&mut self, limit: usize) {
+    self.backup_limit = limit;
+  }
+
+  fn backup(&self) -> Result<PathBuf, Error> {
+    let backup_path = backup::new_backup_path(&self.path)?;
+    let connection = self.connection.lock().unwrap();
+    connection.execute("VACUUM INTO ?1", params![backup_path.to_string_lossy()])?;
+    if self.backup_limit > 0 {
+      backup::prune(&self.path, self.backup_limit)?;
+    };
+
+    Ok(backup_path)
+  }
+}
+
+/// Brings the schema up to date, running each missing migration in its own transaction
+fn migrate_schema(connection: &mut Connection) -> Result<(), Error> {
+  let version: u32 = connection.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+  if version as usize > MIGRATIONS.len() {
+    let message = format!("database has schema version {}, but this build only understands up to version {}", version, MIGRATIONS.len());
+    return Err(Error::Invalid(message));
+  };
+
+  for (index, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+    let transaction = connection.transaction()?;
+    transaction.execute_batch(migration)?;
+    transaction.pragma_update(None, "user_version", index as u32 + 1)?;
+    transaction.commit()?;
+    log!(Debug, "Upgraded database schema to version {}", index + 1);
+  };
+
+  Ok(())
+}
+
+fn insert_greeted(transaction: &Transaction, user_ids: &HashSet<UserId>) -> Result<(), Error> {
+  let mut statement = transaction.prepare("INSERT OR IGNORE INTO greeted_users (user_id, greeted_at) VALUES (?1, ?2)")?;
+  let now = now();
+  for user_id in user_ids {
+    statement.execute(params![user_id.0 as i64, now])?;
+  };
+
+  Ok(())
+}
+
+fn insert_outbox(transaction: &Transaction, items: &[OutboxItem]) -> Result<(), Error> {
+  let mut statement = transaction.prepare("INSERT INTO outbox (id, item) VALUES (?1, ?2)")?;
+  for item in items {
+    statement.execute(params![item.id as i64, serde_json::to_string(item)?])?;
+  };
+
+  Ok(())
+}
+
+fn insert_recommendations(transaction: &Transaction, recommendations: &[Recommendation]) -> Result<(), Error> {
+  let mut statement = transaction.prepare("INSERT INTO recommendations (id, recommendation) VALUES (?1, ?2)")?;
+  for recommendation in recommendations {
+    statement.execute(params![recommendation.id as i64, serde_json::to_string(recommendation)?])?;
+  };
+
+  Ok(())
+}
+
+fn now() -> i64 {
+  SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs() as i64)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn greeted_users_round_trip() {
+    let path = std::env::temp_dir().join(format!("sentinel-test-{}.db", std::process::id()));
+    let mut store = SqliteStore::create(&path).unwrap();
+    assert!(store.should_greet(UserId(1)).unwrap());
+    assert!(store.register_greeted(UserId(1)).unwrap());
+    assert!(!store.register_greeted(UserId(1)).unwrap());
+    assert!(!store.should_greet(UserId(1)).unwrap());
+
+    store.set_greeted([UserId(2), UserId(3)].iter().copied().collect()).unwrap();
+    drop(store);
+
+    let store = SqliteStore::open(&path).unwrap();
+    let persist = store.snapshot().unwrap();
+    assert_eq!(persist.greeted_users, [UserId(2), UserId(3)].iter().copied().collect());
+    drop(store);
+
+    for extension in ["", "-wal", "-shm"].iter() {
+      let _ = std::fs::remove_file(format!("{}{}", path.display(), extension));
+    };
+  }
+}
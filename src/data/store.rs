@@ -0,0 +1,106 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use serenity::model::id::UserId;
+
+use crate::error::Error;
+use super::backup;
+use super::format::Format;
+use super::outbox::OutboxItem;
+use super::persist::{Persist, PersistFile};
+use super::recommend::Recommendation;
+use super::sqlite::{self, SqliteStore};
+
+/// A backend that keeps the bot's persistent state.
+///
+/// Changes made through a store are only guaranteed to be on disk once `commit` returns,
+/// although some backends (like SQLite) write every change as it is made.
+pub trait Store: Send + Sync {
+  /// The file (or database) the store reads from and writes to
+  fn path(&self) -> &Path;
+
+  /// A short name for the backend, for use in messages
+  fn backend(&self) -> &'static str;
+
+  fn should_greet(&self, user_id: UserId) -> Result<bool, Error>;
+
+  /// Marks a user as greeted, returning false if they already were
+  fn register_greeted(&mut self, user_id: UserId) -> Result<bool, Error>;
+
+  /// Replaces the set of greeted users
+  fn set_greeted(&mut self, user_ids: HashSet<UserId>) -> Result<(), Error>;
+
+  /// Failed Discord operations waiting to be retried
+  fn outbox(&self) -> Result<Vec<OutboxItem>, Error>;
+
+  /// Replaces the outbox
+  fn set_outbox(&mut self, items: Vec<OutboxItem>) -> Result<(), Error>;
+
+  /// Rank recommendations, open or decided
+  fn recommendations(&self) -> Result<Vec<Recommendation>, Error>;
+
+  /// Replaces the rank recommendations
+  fn set_recommendations(&mut self, recommendations: Vec<Recommendation>) -> Result<(), Error>;
+
+  /// Reads out the entire state
+  fn snapshot(&self) -> Result<Persist, Error>;
+
+  /// Replaces the entire state
+  fn replace(&mut self, persist: Persist) -> Result<(), Error>;
+
+  /// Picks up changes made to the store from outside the bot, returning whether anything changed
+  fn refresh(&mut self) -> Result<bool, Error>;
+
+  /// Makes sure every change so far is written to disk
+  fn commit(&self) -> Result<(), Error>;
+
+  /// Sets how many backups to keep, 0 disables automatic backups
+  fn set_backup_limit(&mut self, limit: usize);
+
+  /// Writes a backup of the current state, returning where it was written
+  fn backup(&self) -> Result<PathBuf, Error>;
+
+  /// Lists existing backups, newest first
+  fn backups(&self) -> Result<Vec<PathBuf>, Error> {
+    backup::list(self.path())
+  }
+}
+
+/// Whether the store at this path is an SQLite database rather than a persist file
+pub fn is_sqlite(path: impl AsRef<Path>) -> bool {
+  path.as_ref().extension()
+    .and_then(|extension| extension.to_str())
+    .is_some_and(|extension| sqlite::EXTENSIONS.contains(&extension))
+}
+
+/// Opens an existing store, picking the backend by extension
+pub fn open(path: impl AsRef<Path>, format: Option<Format>) -> Result<Box<dyn Store>, Error> {
+  Ok(match is_sqlite(&path) {
+    true => Box::new(SqliteStore::open(path)?),
+    false => Box::new(PersistFile::open(path, format)?)
+  })
+}
+
+/// Opens a store, creating it with default contents if it doesn't exist
+pub fn create_or_default(path: impl AsRef<Path>, format: Option<Format>) -> Result<Box<dyn Store>, Error> {
+  Ok(match is_sqlite(&path) {
+    true => Box::new(SqliteStore::create(path)?),
+    false => Box::new(PersistFile::create_or_default(path, format)?)
+  })
+}
+
+/// Copies the entire state of one store into a new store at `path`, which may use another backend
+pub fn convert(source: &dyn Store, path: impl AsRef<Path>, format: Option<Format>) -> Result<Box<dyn Store>, Error> {
+  let persist = source.snapshot()?;
+  let mut store: Box<dyn Store> = match is_sqlite(&path) {
+    true => Box::new(SqliteStore::create(&path)?),
+    false => {
+      let format = format.or_else(|| Format::from_path(&path)).unwrap_or(Format::Json);
+      Box::new(PersistFile::new(&path, format, Persist::default()))
+    }
+  };
+
+  store.replace(persist)?;
+  store.commit()?;
+  Ok(store)
+}
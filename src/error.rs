@@ -1,17 +1,21 @@
 error_enum!{
   pub enum Error {
     Io(std::io::Error),
-    Format(singlefile::serde_multi::Error),
+    Json(serde_json::Error),
     Serenity(serenity::prelude::SerenityError),
+    Sqlite(rusqlite::Error),
+    Invalid(String),
     Custom(&'static str)
   }
 }
 
-impl From<singlefile::Error> for Error {
-  fn from(value: singlefile::Error) -> Error {
-    match value {
-      singlefile::Error::Io(err) => Error::Io(err.into()),
-      singlefile::Error::Format(err) => Error::Format(err)
+impl Error {
+  /// A message describing the error that is suitable for showing to users
+  pub fn describe(&self) -> String {
+    match self {
+      Error::Invalid(message) => message.clone(),
+      Error::Custom(message) => message.to_string(),
+      err => format!("{:?}", err)
     }
   }
 }
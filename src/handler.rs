@@ -1,18 +1,19 @@
 use std::collections::HashSet;
 use std::sync::Arc;
 
-use singlefile::serde_multi::formats::json::Json;
 use serenity::{
   prelude::*,
   client::bridge::gateway::{
-    ShardManager, GatewayIntents
+    ShardManager, GatewayIntents,
+    event::ShardStageUpdateEvent
   },
+  gateway::ConnectionStage,
   framework::standard::StandardFramework,
   http::Http,
   model::{
     id::{RoleId, GuildId},
-    guild::{Member},
-    channel::{Reaction},
+    guild::{Guild, Member},
+    channel::{Reaction, ReactionType},
     gateway::Ready,
     event::ResumedEvent,
     misc::Mention
@@ -20,10 +21,23 @@ use serenity::{
 };
 
 use crate::commands::groups::*;
-use crate::data::config::{Config, ConfigContainer, ConfigFile};
-use crate::data::persist::{PersistContainer, PersistFile};
+use crate::cli::Options;
+use crate::data::config::{ConfigContainer, ConfigFile, SharedConfig};
+use crate::commands::{change_rank, Authority, RankScheme, RoleChange};
+use crate::data::outbox::Operation;
+use crate::data::persist::PersistContainer;
+use crate::data::recommend::{Recommendation, Status};
+use crate::data::store;
 use crate::error::Error;
+use crate::health::{Health, HealthContainer};
+use crate::history::{History, HistoryContainer};
+use crate::metrics::{Metrics, MetricsContainer, ObserveExt};
+use crate::outbox::{Outbox, OutboxContainer};
+use crate::queue::{MemberQueue, MemberQueueContainer};
+use crate::recommend::{APPROVE, DENY, Recommendations, RecommendationsContainer};
+use crate::shutdown::{Shutdown, ShutdownContainer};
 use crate::util::ResultExt;
+use crate::writer::{PersistWriter, PersistWriterContainer};
 
 
 
@@ -31,46 +45,98 @@ pub struct Handler;
 
 #[serenity::async_trait]
 impl EventHandler for Handler {
-  async fn ready(&self, _: Context, ready: Ready) {
-    println!("Bot {} ({}) is connected", ready.user.name, ready.user.id);
+  async fn ready(&self, ctx: Context, ready: Ready) {
+    log!(Info, "Bot {} ({}) is connected", ready.user.name, ready.user.id);
+    data_get::<HealthContainer>(&ctx).await.mark_ready();
   }
 
   async fn resume(&self, _: Context, _: ResumedEvent) {
-    println!("Bot resumed");
+    log!(Info, "Bot resumed");
   }
 
   async fn cache_ready(&self, ctx: Context, _: Vec<GuildId>) {
-    let config = data_get::<ConfigContainer>(&ctx).await;
-    let config_lock = config.read().await;
-    let (channel_id, message_id) = config_lock.role_menu;
+    data_get::<HealthContainer>(&ctx).await.mark_cache_ready();
+    let config = data_get::<ConfigContainer>(&ctx).await.load();
+    let (channel_id, message_id) = config.role_menu;
     if let Ok(message) = ctx.http.get_message(channel_id.into(), message_id.into()).await {
-      for role_position in config_lock.role_menu_positions.iter() {
+      for role_position in config.role_menu_positions.iter() {
         message.react(&ctx, role_position.emoji.clone()).await.report();
       };
     } else {
-      println!("Error: Couldn't find role menu message");
+      log!(Error, "Error: Couldn't find role menu message");
     };
   }
 
   async fn guild_unavailable(&self, ctx: Context, guild_id: GuildId) {
     let guild_name = ctx.cache.guild(guild_id).await
       .map_or("?".to_owned(), |g| g.name);
-    println!("Guild {} ({}) is unavailable", guild_name, guild_id);
+    log!(Warn, "Guild {} ({}) is unavailable", guild_name, guild_id);
+    // Only the configured guild going away affects health
+    let config = data_get::<ConfigContainer>(&ctx).await.load();
+    if config.is_configured_guild(Some(guild_id)) {
+      data_get::<HealthContainer>(&ctx).await.mark_guild_available(guild_id, false);
+    };
+  }
+
+  async fn guild_create(&self, ctx: Context, guild: Guild, _: bool) {
+    data_get::<HealthContainer>(&ctx).await.mark_guild_available(guild.id, true);
+    let config = data_get::<ConfigContainer>(&ctx).await.load();
+    if !config.is_configured_guild(Some(guild.id)) {
+      match config.leave_other_guilds {
+        true => {
+          log!(Warn, "Leaving guild {} ({}), which is not the configured guild", guild.name, guild.id);
+          guild.leave(&ctx).await.report_with("Failed to leave guild");
+        },
+        false => log!(Warn, "Joined guild {} ({}), which is not the configured guild; ignoring it", guild.name, guild.id)
+      };
+    };
+  }
+
+  async fn shard_stage_update(&self, ctx: Context, event: ShardStageUpdateEvent) {
+    let connected = event.new == ConnectionStage::Connected;
+    data_get::<HealthContainer>(&ctx).await.mark_connected(connected);
   }
 
   async fn reaction_add(&self, ctx: Context, react: Reaction) {
-    let config = data_get::<ConfigContainer>(&ctx).await;
-    let config_lock = config.read().await;
+    // Ignore reactions while shutting down
+    let shutdown = data_get::<ShutdownContainer>(&ctx).await;
+    let _in_flight = match shutdown.guard() {
+      Some(in_flight) => in_flight,
+      None => return
+    };
+
+    let config = data_get::<ConfigContainer>(&ctx).await.load();
 
     // Filter to reactions in the server on the reaction menu message
-    if config_lock.is_role_menu_reaction(&react) {
+    if config.is_configured_guild(react.guild_id) && config.is_role_menu_reaction(&react) {
       let user_id = react.user_id.unwrap();
       let guild_id = react.guild_id.unwrap();
 
       if let Some(member) = ctx.cache.member(guild_id, user_id).await {
         if member.user.bot { return; }; // Ignore reactions from bots
-        maybe_grant_position(ctx, &config_lock, member, react).await;
+        data_get::<MetricsContainer>(&ctx).await.record_role_menu_reaction();
+
+        // Wait for any other role changes for this member, then work from their current roles
+        let queue = data_get::<MemberQueueContainer>(&ctx).await;
+        let key = format!("menu:{}", react.emoji);
+        queue.run(guild_id, user_id, Some(key), || async move {
+          match ctx.http.get_member(guild_id.0, user_id.0).await {
+            Ok(member) => maybe_grant_position(ctx, &config, member, react).await,
+            Err(err) => log!(Error, "Couldn't fetch member {}: {:?}", user_id, err)
+          };
+        }).await;
       };
+    } else if config.is_configured_guild(react.guild_id) && is_ballot_reaction(&config, &react) {
+      ballot_vote(ctx, &config, react).await;
+    };
+  }
+
+  async fn reaction_remove(&self, ctx: Context, react: Reaction) {
+    let config = data_get::<ConfigContainer>(&ctx).await.load();
+    if config.is_configured_guild(react.guild_id) && is_ballot_reaction(&config, &react) {
+      let approve = react.emoji == ReactionType::Unicode(APPROVE.to_owned());
+      let recommendations = data_get::<RecommendationsContainer>(&ctx).await;
+      recommendations.retract((react.channel_id, react.message_id), react.user_id.unwrap(), approve);
     };
   }
 }
@@ -81,12 +147,30 @@ impl TypeMapKey for ShardManagerContainer {
   type Value = Arc<Mutex<ShardManager>>;
 }
 
-pub async fn launch() -> Result<(), Error> {
-  let config = ConfigFile::open("config.json", Json)?;
-  let persist = PersistFile::create_or_default("persist.json", Json)?;
-  let http = Http::new_with_token(&config.token);
+pub async fn launch(options: &Options) -> Result<(), Error> {
+  let config = ConfigFile::open(options.config_path(), options.format)?;
+  config.check_permissions()?;
+  config.validate()?;
+  for key in crate::commands::unknown_permission_keys(&config) {
+    log!(Warn, "Permission entry `{}` does not match any command or group", key);
+  };
+
+  let token = config.token.clone().unwrap();
+  let persist_path = options.persist_path(config.format());
+  let mut persist = store::create_or_default(persist_path, None)?;
+  persist.set_backup_limit(config.persist_backups);
+  let outbox_items = persist.outbox()?;
+  let recommendations = persist.recommendations()?;
+  let http = Http::new_with_token(&token);
   let me = http.get_current_user().await?.id;
 
+  if options.dry_run {
+    let (channel_id, message_id) = config.role_menu;
+    http.get_message(channel_id.0, message_id.0).await?;
+    log!(Info, "Dry run: config and persist loaded, signed in as {}, role menu found", me);
+    return Ok(());
+  };
+
   let framework = StandardFramework::new()
     .configure(|cfg| {
       cfg
@@ -94,26 +178,73 @@ pub async fn launch() -> Result<(), Error> {
         .owners(config.owners.clone())
         .prefix("$")
     })
+    .before(crate::commands::before_command)
+    .after(crate::commands::after_command)
     .group(&OWNER_GROUP)
+    .group(&CONFIGEDIT_GROUP)
     .group(&ADMIN_GROUP)
     .group(&GENERAL_GROUP);
 
-  let mut client = Client::builder(&config.token)
+  let mut client = Client::builder(&token)
     .event_handler(Handler)
     .framework(framework)
     .intents(intents())
     .await?;
   
+  let http_address = config.http_address;
+  let systemd_notify = config.systemd_notify;
+  let health = Arc::new(Health::new(systemd_notify));
+  let shutdown = Arc::new(Shutdown::new());
+  let persist = Arc::new(RwLock::new(persist));
+  let metrics = Arc::new(Metrics::new());
+  let (writer, writer_task) = PersistWriter::new(Arc::clone(&persist), Arc::clone(&metrics));
+  tokio::spawn(writer_task);
+  let writer = Arc::new(writer);
+  let queue = Arc::new(MemberQueue::new());
+  let outbox = Arc::new(Outbox::new(outbox_items, Arc::clone(&writer), Arc::clone(&queue), Arc::clone(&metrics)));
+  let recommendations = Arc::new(Recommendations::new(recommendations, Arc::clone(&writer)));
+
   let mut data = client.data.write().await;
-  data.insert::<ConfigContainer>(Arc::new(RwLock::new(config)));
-  data.insert::<PersistContainer>(Arc::new(RwLock::new(persist)));
+  data.insert::<ConfigContainer>(Arc::new(SharedConfig::new(config)));
+  data.insert::<PersistContainer>(persist);
+  data.insert::<PersistWriterContainer>(writer);
   data.insert::<ShardManagerContainer>(Arc::clone(&client.shard_manager));
+  data.insert::<MetricsContainer>(metrics);
+  data.insert::<MemberQueueContainer>(queue);
+  data.insert::<OutboxContainer>(Arc::clone(&outbox));
+  data.insert::<RecommendationsContainer>(recommendations);
+  data.insert::<HistoryContainer>(Arc::new(History::new()));
+  data.insert::<HealthContainer>(Arc::clone(&health));
+  data.insert::<ShutdownContainer>(Arc::clone(&shutdown));
   std::mem::drop(data);
 
+  if let Some(http_address) = http_address {
+    let data = Arc::clone(&client.data);
+    tokio::spawn(async move {
+      crate::server::serve(http_address, data).await
+        .report_with("HTTP listener failed");
+    });
+  };
+
+  tokio::spawn(crate::outbox::run(outbox, Arc::clone(&client.cache_and_http.http)));
+
+  if systemd_notify {
+    tokio::spawn(crate::systemd::run_watchdog(Arc::clone(&health)));
+  };
+
+  let data = Arc::clone(&client.data);
+  let cache = Arc::clone(&client.cache_and_http.cache);
+  tokio::spawn(async move {
+    crate::watcher::run(data, cache).await
+      .report_with("File watcher failed");
+  });
+
+  let data = Arc::clone(&client.data);
+  let http = Arc::clone(&client.cache_and_http.http);
   let shard_manager = Arc::clone(&client.shard_manager);
   tokio::spawn(async move {
-    tokio::signal::ctrl_c().await.unwrap();
-    shard_manager.lock().await.shutdown_all().await;
+    crate::shutdown::run(shutdown, data, http, shard_manager).await
+      .report_with("Failed to shut down");
   });
 
   client.start().await?;
@@ -125,10 +256,134 @@ pub async fn launch() -> Result<(), Error> {
 #[inline]
 pub async fn data_get<K>(ctx: &Context) -> K::Value
 where K: TypeMapKey, K::Value: Clone {
-  ctx.data.read().await.get::<K>().unwrap().clone()
+  data_get_from::<K>(&ctx.data).await
 }
 
-async fn maybe_grant_position(ctx: Context, config: &Config, member: Member, react: Reaction) {
+#[inline]
+pub async fn data_get_from<K>(data: &RwLock<TypeMap>) -> K::Value
+where K: TypeMapKey, K::Value: Clone {
+  data.read().await.get::<K>().unwrap().clone()
+}
+
+/// Whether a reaction is an approve or deny reaction in the review channel
+fn is_ballot_reaction(config: &ConfigFile, react: &Reaction) -> bool {
+  let in_review_channel = config.review.as_ref()
+    .is_some_and(|review| review.channel == react.channel_id);
+  in_review_channel && [APPROVE, DENY].iter()
+    .any(|&emoji| react.emoji == ReactionType::Unicode(emoji.to_owned()))
+}
+
+async fn ballot_vote(ctx: Context, config: &ConfigFile, react: Reaction) {
+  let recommendations = data_get::<RecommendationsContainer>(&ctx).await;
+  let recommendation = match recommendations.find_open((react.channel_id, react.message_id)) {
+    Some(recommendation) => recommendation,
+    None => return
+  };
+
+  let (guild_id, user_id) = (react.guild_id.unwrap(), react.user_id.unwrap());
+  // Approvers who haven't been seen since the bot started aren't cached, so fetch them instead
+  let member = match ctx.cache.member(guild_id, user_id).await {
+    Some(member) => member,
+    None => match ctx.http.get_member(guild_id.0, user_id.0).await {
+      Ok(member) => member,
+      Err(err) => {
+        log!(Error, "Couldn't fetch member {} to count their vote: {:?}", user_id, err);
+        return;
+      }
+    }
+  };
+
+  if member.user.bot { return }; // Ignore reactions from bots
+
+  // Only count votes from members ranked high enough, and never from the member being recommended
+  let eligible = user_id != recommendation.user_id &&
+    (config.owners.contains(&user_id) || config.is_approver(&member.roles));
+  if !eligible {
+    let outbox = data_get::<OutboxContainer>(&ctx).await;
+    outbox.perform(&ctx.http, Operation::DeleteReaction {
+      channel_id: react.channel_id,
+      message_id: react.message_id,
+      user_id,
+      emoji: react.emoji
+    }).await;
+    return;
+  };
+
+  let approve = react.emoji == ReactionType::Unicode(APPROVE.to_owned());
+  let needed = config.review.as_ref().map_or(1, |review| review.approvals);
+  if let Some(decided) = recommendations.vote(recommendation.id, user_id, approve, needed) {
+    decide_recommendation(ctx, config, decided).await;
+  };
+}
+
+/// Carries out a recommendation once it has been approved or denied, and posts the outcome under its ballot
+async fn decide_recommendation(ctx: Context, config: &ConfigFile, recommendation: Recommendation) {
+  let outbox = data_get::<OutboxContainer>(&ctx).await;
+  let (channel_id, _) = recommendation.ballot.unwrap();
+  let mention = Mention::from(recommendation.user_id).to_string();
+  let id = recommendation.id;
+
+  let outcome = match recommendation.status {
+    Status::Approved => {
+      let (guild_id, user_id) = (config.guild, recommendation.user_id);
+      let queue = data_get::<MemberQueueContainer>(&ctx).await;
+      let result = queue.run(guild_id, user_id, Some(format!("recommend:{}", id)), || async {
+        let member = ctx.http.get_member(guild_id.0, user_id.0).await?;
+        // The approvers have signed off, so the rank change is made with the bot's own authority
+        let change = change_rank(config, &Authority::Owner, &member, RankScheme::Named(&recommendation.rank));
+        let mut deferred = false;
+        if let RoleChange::Set(roles) = &change {
+          let old_roles: HashSet<RoleId> = member.roles.iter().cloned().collect();
+          let operation = Operation::EditRoles {
+            guild_id,
+            user_id,
+            add: roles.difference(&old_roles).copied().collect(),
+            remove: old_roles.difference(roles).copied().collect()
+          };
+
+          match member.edit(&ctx, |edit| edit.roles(roles.iter())).await {
+            Ok(_) => {
+              // Recorded under the recommender, so they can take it back with `$undo`
+              let history = data_get::<HistoryContainer>(&ctx).await;
+              let description = format!("Recommendation #{}: {} for {}", id, member.display_name(), recommendation.rank);
+              history.record(history.new_action(), guild_id, user_id, recommendation.recommender, description, old_roles, roles.clone());
+            },
+            Err(err) => {
+              outbox.defer(operation, &err);
+              deferred = true;
+            }
+          };
+        };
+
+        Ok::<(RoleChange, bool), SerenityError>((change, deferred))
+      }).await;
+
+      match result {
+        Some(Ok((RoleChange::Set(_), false))) => format!("Recommendation #{} was approved, {} is now {}", id, mention, recommendation.rank),
+        Some(Ok((RoleChange::Set(_), true))) => format!(
+          "Recommendation #{} was approved, changing {}'s rank to {} failed and is pending retry (see `$outbox`)",
+          id, mention, recommendation.rank
+        ),
+        other => {
+          match other {
+            Some(Err(err)) => log!(Error, "Couldn't carry out recommendation #{}: {:?}", id, err),
+            _ => log!(Warn, "Couldn't carry out recommendation #{}, the member has no rank or already has it", id)
+          };
+
+          data_get::<RecommendationsContainer>(&ctx).await.set_status(id, Status::Failed);
+          format!("Recommendation #{} was approved, but {}'s rank couldn't be changed", id, mention)
+        }
+      }
+    },
+    _ => format!("Recommendation #{} was denied", id)
+  };
+
+  outbox.perform(&ctx.http, Operation::SendMessage { channel_id, content: outcome }).await;
+}
+
+async fn maybe_grant_position(ctx: Context, config: &ConfigFile, member: Member, react: Reaction) {
+  let metrics = data_get::<MetricsContainer>(&ctx).await;
+  let outbox = data_get::<OutboxContainer>(&ctx).await;
   let positions = config.get_member_positions(&member.roles);
   let current_position = positions.first().copied();
 
@@ -166,37 +421,66 @@ async fn maybe_grant_position(ctx: Context, config: &Config, member: Member, rea
       };
     };
 
-    member.edit(&ctx, |edit| edit.roles(roles)).await.report_with("Couldn't edit roles");
+    // Retry later if the edit fails, relative to whatever roles they have by then
+    let old_roles: HashSet<RoleId> = member.roles.iter().cloned().collect();
+    let operation = Operation::EditRoles {
+      guild_id: member.guild_id,
+      user_id: member.user.id,
+      add: roles.difference(&old_roles).copied().collect(),
+      remove: old_roles.difference(&roles).copied().collect()
+    };
+
+    match member.edit(&ctx, |edit| edit.roles(roles.iter())).await.observe(&metrics) {
+      Ok(_) if roles != old_roles => {
+        let history = data_get::<HistoryContainer>(&ctx).await;
+        let description = format!("{} picking {} from the role menu", member.display_name(), new_position.name);
+        history.record(history.new_action(), member.guild_id, member.user.id, member.user.id, description, old_roles, roles);
+      },
+      Ok(_) => (),
+      Err(err) => outbox.defer(operation, &err)
+    };
 
     // Do nothing else if the user tried to give themselves a role they already have
     if Some(new_position) == current_position { return };
 
     // Delete their other reactions
-    let guild_channel = ctx.cache.guild_channel(react.channel_id).await.unwrap();
     for &old_position in positions.iter() {
       if old_position != new_position {
         if let Some(emoji) = config.get_role_menu_emoji(&old_position.name) {
-          guild_channel.delete_reaction(&ctx, react.message_id, Some(member.user.id), emoji).await.report();
+          outbox.perform(&ctx.http, Operation::DeleteReaction {
+            channel_id: react.channel_id,
+            message_id: react.message_id,
+            user_id: member.user.id,
+            emoji
+          }).await;
         };
       };
     };
 
     // Send a greeting in the greeting channel if the correct criteria matches
-    let persist = data_get::<PersistContainer>(&ctx).await;
-    let mut persist_lock = persist.write().await;
-    if persist_lock.should_greet(member.user.id) {
-      if config.greetable_positions.contains(&new_position.name) {
-        let mention = Mention::from(member.user.id).to_string();
-        let greeting = config.get_greeting().replace("{mention}", &mention);
-        config.greeting_channel.say(&ctx, greeting).await.report_with("Failed to send greeting");
-        persist_lock.register_greeted(member.user.id);
-        persist_lock.commit().report_with("Failed to commit config");
+    if config.greetable_positions.contains(&new_position.name) {
+      let writer = data_get::<PersistWriterContainer>(&ctx).await;
+      match writer.claim_greeting(member.user.id).await {
+        Ok(true) => {
+          let mention = Mention::from(member.user.id).to_string();
+          let greeting = config.get_greeting().replace("{mention}", &mention);
+          outbox.perform(&ctx.http, Operation::SendMessage {
+            channel_id: config.greeting_channel,
+            content: greeting
+          }).await;
+        },
+        Ok(false) => (),
+        Err(err) => log!(Error, "Failed to read persist: {:?}", err)
       };
     };
   } else {
     // User has a position not on the role menu; don't change their roles
-    let guild_channel = ctx.cache.guild_channel(react.channel_id).await.unwrap();
-    guild_channel.delete_reaction(&ctx, react.message_id, Some(member.user.id), react.emoji).await.report();
+    outbox.perform(&ctx.http, Operation::DeleteReaction {
+      channel_id: react.channel_id,
+      message_id: react.message_id,
+      user_id: member.user.id,
+      emoji: react.emoji
+    }).await;
   };
 }
 
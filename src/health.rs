@@ -0,0 +1,122 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex as StdMutex;
+use std::time::{Duration, Instant};
+
+use serenity::{
+  prelude::*,
+  model::id::GuildId
+};
+
+/// How long the bot may be disconnected from the gateway before it is considered unhealthy
+const DISCONNECT_GRACE: Duration = Duration::from_secs(120);
+
+pub struct HealthContainer;
+
+impl TypeMapKey for HealthContainer {
+  type Value = Arc<Health>;
+}
+
+#[derive(Debug)]
+pub struct Health {
+  systemd_notify: bool,
+  ready: AtomicBool,
+  cache_ready: AtomicBool,
+  notified_ready: AtomicBool,
+  disconnected_since: StdMutex<Option<Instant>>,
+  unavailable_guilds: StdMutex<HashSet<GuildId>>
+}
+
+impl Health {
+  pub fn new(systemd_notify: bool) -> Health {
+    Health {
+      systemd_notify,
+      ready: AtomicBool::new(false),
+      cache_ready: AtomicBool::new(false),
+      notified_ready: AtomicBool::new(false),
+      disconnected_since: StdMutex::new(Some(Instant::now())),
+      unavailable_guilds: StdMutex::new(HashSet::new())
+    }
+  }
+
+  pub fn mark_ready(&self) {
+    self.ready.store(true, Ordering::SeqCst);
+    self.update();
+  }
+
+  pub fn mark_cache_ready(&self) {
+    self.cache_ready.store(true, Ordering::SeqCst);
+    self.update();
+  }
+
+  pub fn mark_connected(&self, connected: bool) {
+    let mut disconnected_since = self.disconnected_since.lock().unwrap();
+    match (connected, *disconnected_since) {
+      (true, _) => *disconnected_since = None,
+      (false, None) => *disconnected_since = Some(Instant::now()),
+      (false, Some(_)) => ()
+    };
+
+    std::mem::drop(disconnected_since);
+    self.update();
+  }
+
+  pub fn mark_guild_available(&self, guild_id: GuildId, available: bool) {
+    let mut unavailable_guilds = self.unavailable_guilds.lock().unwrap();
+    if available {
+      unavailable_guilds.remove(&guild_id);
+    } else {
+      unavailable_guilds.insert(guild_id);
+    };
+
+    std::mem::drop(unavailable_guilds);
+    self.update();
+  }
+
+  pub fn is_connected(&self) -> bool {
+    self.disconnected_since.lock().unwrap().is_none()
+  }
+
+  /// Whether the process is running and has not been disconnected from the gateway for too long.
+  /// A guild being unavailable is an outage on Discord's side that restarting won't fix,
+  /// so it only affects readiness.
+  pub fn is_live(&self) -> bool {
+    let disconnected_too_long = self.disconnected_since.lock().unwrap()
+      .is_some_and(|since| since.elapsed() > DISCONNECT_GRACE);
+    !disconnected_too_long
+  }
+
+  /// Whether the bot is fully connected to discord and able to respond to events
+  pub fn is_ready(&self) -> bool {
+    self.ready.load(Ordering::SeqCst) &&
+    self.cache_ready.load(Ordering::SeqCst) &&
+    self.is_connected() &&
+    self.unavailable_guilds.lock().unwrap().is_empty()
+  }
+
+  pub fn describe(&self) -> String {
+    format!(
+      "ready: {}\ncache_ready: {}\nconnected: {}\nunavailable_guilds: {}\n",
+      self.ready.load(Ordering::SeqCst),
+      self.cache_ready.load(Ordering::SeqCst),
+      self.is_connected(),
+      self.unavailable_guilds.lock().unwrap().len()
+    )
+  }
+
+  pub fn notify_stopping(&self) {
+    if self.systemd_notify {
+      crate::systemd::notify("STOPPING=1");
+    };
+  }
+
+  fn update(&self) {
+    if self.systemd_notify && self.is_ready() {
+      // Only tell systemd we're ready the first time
+      if !self.notified_ready.swap(true, Ordering::SeqCst) {
+        crate::systemd::notify("READY=1");
+      };
+    };
+  }
+}
@@ -0,0 +1,106 @@
+use std::collections::{HashSet, VecDeque};
+use std::sync::Arc;
+use std::sync::Mutex as StdMutex;
+
+use serenity::{
+  prelude::*,
+  model::id::{GuildId, RoleId, UserId}
+};
+
+/// How many role changes to remember, oldest are forgotten first
+const MAX_CHANGES: usize = 500;
+
+pub struct HistoryContainer;
+
+impl TypeMapKey for HistoryContainer {
+  type Value = Arc<History>;
+}
+
+/// A change the bot made to a member's roles
+#[derive(Debug, Clone)]
+pub struct Change {
+  pub id: u64,
+  /// Changes made by the same command (or reaction) share an action
+  pub action: u64,
+  pub guild_id: GuildId,
+  pub user_id: UserId,
+  /// Whoever asked for the change
+  pub actor: UserId,
+  /// What was done, like "Promoting Scotty"
+  pub description: String,
+  pub before: HashSet<RoleId>,
+  pub after: HashSet<RoleId>,
+  pub undone: bool
+}
+
+impl Change {
+  /// Roles the change gave the member
+  pub fn added(&self) -> impl Iterator<Item = &RoleId> {
+    self.after.difference(&self.before)
+  }
+
+  /// Roles the change took from the member
+  pub fn removed(&self) -> impl Iterator<Item = &RoleId> {
+    self.before.difference(&self.after)
+  }
+}
+
+/// The most recent role changes the bot made, so that they can be undone
+#[derive(Debug, Default)]
+pub struct History {
+  changes: StdMutex<VecDeque<Change>>,
+  next_id: StdMutex<u64>
+}
+
+impl History {
+  pub fn new() -> History {
+    History::default()
+  }
+
+  /// A fresh id to group changes under
+  pub fn new_action(&self) -> u64 {
+    let mut next_id = self.next_id.lock().unwrap();
+    *next_id += 1;
+    *next_id
+  }
+
+  #[allow(clippy::too_many_arguments)]
+  pub fn record(
+    &self, action: u64, guild_id: GuildId, user_id: UserId, actor: UserId,
+    description: String, before: HashSet<RoleId>, after: HashSet<RoleId>
+  ) {
+    let id = self.new_action();
+    let mut changes = self.changes.lock().unwrap();
+    changes.push_back(Change { id, action, guild_id, user_id, actor, description, before, after, undone: false });
+    while changes.len() > MAX_CHANGES {
+      changes.pop_front();
+    };
+  }
+
+  /// Every change from the last action by an actor that hasn't been undone, newest first
+  pub fn last_action_by(&self, actor: UserId) -> Vec<Change> {
+    let changes = self.changes.lock().unwrap();
+    let action = match changes.iter().rev().find(|change| change.actor == actor && !change.undone) {
+      Some(change) => change.action,
+      None => return Vec::new()
+    };
+
+    changes.iter().rev()
+      .filter(|change| change.action == action && !change.undone)
+      .cloned()
+      .collect()
+  }
+
+  /// The last change to a member that hasn't been undone
+  pub fn last_for(&self, guild_id: GuildId, user_id: UserId) -> Option<Change> {
+    self.changes.lock().unwrap().iter().rev()
+      .find(|change| change.guild_id == guild_id && change.user_id == user_id && !change.undone)
+      .cloned()
+  }
+
+  pub fn mark_undone(&self, id: u64) {
+    if let Some(change) = self.changes.lock().unwrap().iter_mut().find(|change| change.id == id) {
+      change.undone = true;
+    };
+  }
+}
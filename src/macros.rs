@@ -1,13 +1,23 @@
+/// Prints a message if the given log level is enabled
+#[macro_export]
+macro_rules! log {
+  ($level:ident, $($arg:tt)*) => {
+    if $crate::util::log_enabled($crate::util::LogLevel::$level) {
+      println!($($arg)*);
+    }
+  };
+}
+
 #[macro_export]
 macro_rules! ignore {
   ($expr:expr) => {
     if let Err(err) = $expr {
-      println!("Error: {:?}", err);
+      log!(Error, "Error: {:?}", err);
     };
   };
   ($arg:tt, $expr:expr) => {
     if let Err(err) = $expr {
-      println!($arg, err);
+      log!(Error, $arg, err);
     };
   };
 }
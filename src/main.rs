@@ -1,19 +1,51 @@
 #[macro_use] extern crate serde;
 #[macro_use] extern crate util_macros;
+extern crate arc_swap;
 extern crate serenity;
-extern crate singlefile;
+extern crate serde_json;
+extern crate ron;
+extern crate rusqlite;
+extern crate toml;
 extern crate tokio;
 
 #[macro_use] mod macros;
+mod cli;
 mod commands;
 mod data;
 mod error;
 mod handler;
+mod health;
+mod history;
+mod metrics;
+mod outbox;
+mod queue;
+mod recommend;
+mod server;
+mod setup;
+mod shutdown;
+mod systemd;
 mod util;
+mod watcher;
+mod writer;
 
-use crate::util::ResultExt;
+use crate::util::set_log_level;
 
 #[tokio::main]
 async fn main() {
-  crate::handler::launch().await.report();
+  let (command, options) = match crate::cli::parse(std::env::args().skip(1)) {
+    Ok(parsed) => parsed,
+    Err(err) => {
+      println!("Error: {}\n\n{}", err.describe(), crate::cli::USAGE);
+      std::process::exit(2);
+    }
+  };
+
+  if let Some(log_level) = options.log_level {
+    set_log_level(log_level);
+  };
+
+  if let Err(err) = crate::cli::run(command, options).await {
+    println!("Error: {}", err.describe());
+    std::process::exit(1);
+  };
 }
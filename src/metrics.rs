@@ -0,0 +1,262 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex as StdMutex;
+use std::time::{Duration, Instant};
+
+use serenity::{
+  prelude::*,
+  client::bridge::gateway::ShardManager,
+  http::error::Error as HttpError
+};
+
+/// Upper bounds (in seconds) of the persist commit duration histogram buckets
+const COMMIT_BUCKETS: [f64; 8] = [0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25];
+
+pub struct MetricsContainer;
+
+impl TypeMapKey for MetricsContainer {
+  type Value = Arc<Metrics>;
+}
+
+#[derive(Debug)]
+pub struct Metrics {
+  started: Instant,
+  commands: StdMutex<BTreeMap<(String, &'static str), u64>>,
+  role_menu_reactions: AtomicU64,
+  api_errors: StdMutex<BTreeMap<&'static str, u64>>,
+  persist_commits: StdMutex<Histogram>
+}
+
+impl Metrics {
+  pub fn new() -> Metrics {
+    Metrics {
+      started: Instant::now(),
+      commands: StdMutex::new(BTreeMap::new()),
+      role_menu_reactions: AtomicU64::new(0),
+      api_errors: StdMutex::new(BTreeMap::new()),
+      persist_commits: StdMutex::new(Histogram::new(&COMMIT_BUCKETS))
+    }
+  }
+
+  pub fn record_command(&self, command: &str, outcome: &'static str) {
+    let mut commands = self.commands.lock().unwrap();
+    *commands.entry((command.to_owned(), outcome)).or_insert(0) += 1;
+  }
+
+  pub fn record_role_menu_reaction(&self) {
+    self.role_menu_reactions.fetch_add(1, Ordering::Relaxed);
+  }
+
+  pub fn record_api_error(&self, err: &SerenityError) {
+    let mut api_errors = self.api_errors.lock().unwrap();
+    *api_errors.entry(error_kind(err)).or_insert(0) += 1;
+  }
+
+  /// Runs a persist commit, recording how long it took
+  pub fn time_persist_commit<T, F>(&self, f: F) -> T
+  where F: FnOnce() -> T {
+    let start = Instant::now();
+    let out = f();
+    self.persist_commits.lock().unwrap().observe(start.elapsed());
+    out
+  }
+
+  /// Renders all metrics in the Prometheus text exposition format
+  pub async fn render(&self, shard_manager: &Arc<Mutex<ShardManager>>) -> String {
+    let mut out = String::new();
+    self.render_recorded(&mut out);
+
+    writeln!(out, "# HELP sentinel_gateway_latency_seconds Gateway heartbeat latency, by shard").unwrap();
+    writeln!(out, "# TYPE sentinel_gateway_latency_seconds gauge").unwrap();
+    let shard_manager_lock = shard_manager.lock().await;
+    for (shard_id, runner) in shard_manager_lock.runners.lock().await.iter() {
+      if let Some(latency) = runner.latency {
+        writeln!(out, "sentinel_gateway_latency_seconds{{shard=\"{}\"}} {}", shard_id.0, latency.as_secs_f64()).unwrap();
+      };
+    };
+
+    out
+  }
+
+  /// Renders the metrics the bot records itself, everything but the gateway latency
+  fn render_recorded(&self, out: &mut String) {
+    writeln!(out, "# HELP sentinel_uptime_seconds Time since the bot was started").unwrap();
+    writeln!(out, "# TYPE sentinel_uptime_seconds gauge").unwrap();
+    writeln!(out, "sentinel_uptime_seconds {}", self.started.elapsed().as_secs_f64()).unwrap();
+
+    writeln!(out, "# HELP sentinel_commands_total Commands executed, by command and outcome").unwrap();
+    writeln!(out, "# TYPE sentinel_commands_total counter").unwrap();
+    for ((command, outcome), count) in self.commands.lock().unwrap().iter() {
+      writeln!(out, "sentinel_commands_total{{command=\"{}\",outcome=\"{}\"}} {}", escape(command), outcome, count).unwrap();
+    };
+
+    writeln!(out, "# HELP sentinel_role_menu_reactions_total Role menu reactions processed").unwrap();
+    writeln!(out, "# TYPE sentinel_role_menu_reactions_total counter").unwrap();
+    writeln!(out, "sentinel_role_menu_reactions_total {}", self.role_menu_reactions.load(Ordering::Relaxed)).unwrap();
+
+    writeln!(out, "# HELP sentinel_api_errors_total Discord API errors, by kind").unwrap();
+    writeln!(out, "# TYPE sentinel_api_errors_total counter").unwrap();
+    for (kind, count) in self.api_errors.lock().unwrap().iter() {
+      writeln!(out, "sentinel_api_errors_total{{kind=\"{}\"}} {}", kind, count).unwrap();
+    };
+
+    writeln!(out, "# HELP sentinel_persist_commit_seconds Time taken to commit the persist file").unwrap();
+    writeln!(out, "# TYPE sentinel_persist_commit_seconds histogram").unwrap();
+    self.persist_commits.lock().unwrap().render(out, "sentinel_persist_commit_seconds");
+  }
+}
+
+impl Default for Metrics {
+  fn default() -> Metrics {
+    Metrics::new()
+  }
+}
+
+/// Records Discord API errors as they pass by
+pub trait ObserveExt {
+  fn observe(self, metrics: &Metrics) -> Self;
+}
+
+impl<T> ObserveExt for Result<T, SerenityError> {
+  #[inline]
+  fn observe(self, metrics: &Metrics) -> Self {
+    if let Err(err) = &self {
+      metrics.record_api_error(err);
+    };
+
+    self
+  }
+}
+
+#[derive(Debug)]
+struct Histogram {
+  bounds: &'static [f64],
+  buckets: Vec<u64>,
+  count: u64,
+  sum: f64
+}
+
+impl Histogram {
+  fn new(bounds: &'static [f64]) -> Histogram {
+    Histogram {
+      bounds,
+      buckets: vec![0; bounds.len()],
+      count: 0,
+      sum: 0.0
+    }
+  }
+
+  fn observe(&mut self, duration: Duration) {
+    let secs = duration.as_secs_f64();
+    for (bucket, &bound) in self.buckets.iter_mut().zip(self.bounds) {
+      if secs <= bound { *bucket += 1 };
+    };
+
+    self.count += 1;
+    self.sum += secs;
+  }
+
+  fn render(&self, out: &mut String, name: &str) {
+    for (bucket, bound) in self.buckets.iter().zip(self.bounds) {
+      writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, bucket).unwrap();
+    };
+
+    writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, self.count).unwrap();
+    writeln!(out, "{}_sum {}", name, self.sum).unwrap();
+    writeln!(out, "{}_count {}", name, self.count).unwrap();
+  }
+}
+
+fn error_kind(err: &SerenityError) -> &'static str {
+  match err {
+    SerenityError::Http(err) => match &**err {
+      HttpError::UnsuccessfulRequest(response) => match response.status_code.as_u16() {
+        400 => "http_bad_request",
+        401 => "http_unauthorized",
+        403 => "http_forbidden",
+        404 => "http_not_found",
+        429 => "http_rate_limited",
+        500..=599 => "http_server_error",
+        _ => "http_other"
+      },
+      HttpError::Request(_) => "http_request",
+      _ => "http_other"
+    },
+    SerenityError::Gateway(_) => "gateway",
+    SerenityError::Model(_) => "model",
+    SerenityError::Io(_) => "io",
+    SerenityError::Json(_) => "json",
+    _ => "other"
+  }
+}
+
+fn escape(value: &str) -> String {
+  value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn render(metrics: &Metrics) -> String {
+    let mut out = String::new();
+    metrics.render_recorded(&mut out);
+    out
+  }
+
+  #[test]
+  fn counters_are_rendered_with_their_labels() {
+    let metrics = Metrics::new();
+    metrics.record_command("promote", "success");
+    metrics.record_command("promote", "success");
+    metrics.record_command("say \"hi\"", "error");
+    metrics.record_role_menu_reaction();
+    metrics.record_api_error(&SerenityError::Io(std::io::Error::other("closed")));
+
+    let out = render(&metrics);
+    assert!(out.contains("# TYPE sentinel_commands_total counter\n"));
+    assert!(out.contains("\nsentinel_commands_total{command=\"promote\",outcome=\"success\"} 2\n"));
+    assert!(out.contains("\nsentinel_commands_total{command=\"say \\\"hi\\\"\",outcome=\"error\"} 1\n"));
+    assert!(out.contains("\nsentinel_role_menu_reactions_total 1\n"));
+    assert!(out.contains("\nsentinel_api_errors_total{kind=\"io\"} 1\n"));
+  }
+
+  #[test]
+  fn every_sample_follows_its_help_and_type() {
+    let metrics = Metrics::new();
+    metrics.record_command("ping", "success");
+    let out = render(&metrics);
+
+    let mut declared = Vec::new();
+    for line in out.lines() {
+      if let Some(rest) = line.strip_prefix("# TYPE ") {
+        let name = rest.split(' ').next().unwrap();
+        assert!(out.contains(&format!("# HELP {} ", name)));
+        declared.push(name);
+      } else if !line.starts_with('#') {
+        let name = line.split(['{', ' ']).next().unwrap();
+        let family = declared.last().expect("sample before any TYPE line");
+        assert!(name.starts_with(family), "{} isn't part of {}", name, family);
+        assert!(line.rsplit(' ').next().unwrap().parse::<f64>().is_ok(), "bad value in {}", line);
+      };
+    };
+  }
+
+  #[test]
+  fn histogram_buckets_are_cumulative() {
+    let mut histogram = Histogram::new(&[0.01, 0.1]);
+    histogram.observe(Duration::from_millis(5));
+    histogram.observe(Duration::from_millis(50));
+    histogram.observe(Duration::from_millis(500));
+
+    let mut out = String::new();
+    histogram.render(&mut out, "commit");
+    assert_eq!(out, "commit_bucket{le=\"0.01\"} 1\n\
+      commit_bucket{le=\"0.1\"} 2\n\
+      commit_bucket{le=\"+Inf\"} 3\n\
+      commit_sum 0.555\n\
+      commit_count 3\n");
+  }
+}
@@ -0,0 +1,262 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::sync::Mutex as StdMutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serenity::{
+  prelude::*,
+  http::{Http, error::Error as HttpError},
+  model::id::RoleId
+};
+use tokio::sync::Notify;
+
+use crate::data::outbox::{Operation, OutboxItem};
+use crate::metrics::{Metrics, ObserveExt};
+use crate::queue::MemberQueue;
+use crate::writer::{Mutation, PersistWriter};
+
+/// How often to check for operations that are due to be retried
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// Seconds to wait before the first retry, doubled after every failed attempt
+const BASE_BACKOFF: u64 = 5;
+const MAX_BACKOFF: u64 = 60 * 60;
+/// Operations are marked as stuck after failing this many times
+const MAX_ATTEMPTS: u32 = 12;
+
+pub struct OutboxContainer;
+
+impl TypeMapKey for OutboxContainer {
+  type Value = Arc<Outbox>;
+}
+
+/// Discord operations that failed and are waiting to be retried, kept in persist so that
+/// they survive restarts
+pub struct Outbox {
+  items: StdMutex<Vec<OutboxItem>>,
+  writer: Arc<PersistWriter>,
+  queue: Arc<MemberQueue>,
+  metrics: Arc<Metrics>,
+  wake: Notify
+}
+
+impl Outbox {
+  pub fn new(items: Vec<OutboxItem>, writer: Arc<PersistWriter>, queue: Arc<MemberQueue>, metrics: Arc<Metrics>) -> Outbox {
+    Outbox { items: StdMutex::new(items), writer, queue, metrics, wake: Notify::new() }
+  }
+
+  /// Runs an operation, queueing it to be retried if it fails. Role edits wait for the member's
+  /// action queue, so they must not be performed from inside an action for the same member.
+  pub async fn perform(&self, http: &Http, operation: Operation) {
+    if let Err(err) = self.execute(http, &operation).await {
+      self.defer(operation, &err);
+    };
+  }
+
+  /// Queues an operation that has just failed. Operations that failed in a way retrying
+  /// won't fix are kept as stuck, so that they can be looked at with `$outbox`.
+  pub fn defer(&self, operation: Operation, err: &SerenityError) {
+    let stuck = !is_retryable(err);
+    match stuck {
+      true => log!(Error, "Failed to {}, not retrying: {:?}", operation, err),
+      false => log!(Warn, "Failed to {}, will retry: {:?}", operation, err)
+    };
+
+    let mut items = self.items.lock().unwrap();
+    let id = items.iter().map(|item| item.id + 1).max().unwrap_or(1);
+    items.push(OutboxItem {
+      id,
+      operation,
+      attempts: 1,
+      next_attempt: now() + backoff(1),
+      last_error: Some(format!("{:?}", err)),
+      stuck
+    });
+
+    self.save(&items);
+  }
+
+  /// Replaces the items with ones read back from the persist store, after it was changed from
+  /// outside the bot
+  pub fn reload(&self, items: Vec<OutboxItem>) {
+    *self.items.lock().unwrap() = items;
+    self.wake.notify_one();
+  }
+
+  /// A copy of every item in the outbox
+  pub fn items(&self) -> Vec<OutboxItem> {
+    self.items.lock().unwrap().clone()
+  }
+
+  /// Removes the item with the given id, or every stuck item. Returns how many were removed.
+  pub fn clear(&self, id: Option<u64>) -> usize {
+    let mut items = self.items.lock().unwrap();
+    let before = items.len();
+    items.retain(|item| match id {
+      Some(id) => item.id != id,
+      None => !item.stuck
+    });
+
+    let removed = before - items.len();
+    self.save(&items);
+    removed
+  }
+
+  /// Retries the item with the given id, or every stuck item, right away. Returns how many there were.
+  pub fn retry(&self, id: Option<u64>) -> usize {
+    let mut items = self.items.lock().unwrap();
+    let mut count = 0;
+    for item in items.iter_mut().filter(|item| id.map_or(item.stuck, |id| item.id == id)) {
+      item.stuck = false;
+      item.attempts = 0;
+      item.next_attempt = 0;
+      count += 1;
+    };
+
+    self.save(&items);
+    self.wake.notify_one();
+    count
+  }
+
+  fn save(&self, items: &[OutboxItem]) {
+    self.writer.send(Mutation::SetOutbox(items.to_vec()));
+  }
+
+  async fn execute(&self, http: &Http, operation: &Operation) -> Result<(), SerenityError> {
+    let result = match operation {
+      Operation::EditRoles { guild_id, user_id, add, remove } => {
+        let (guild_id, user_id) = (*guild_id, *user_id);
+        // Go through the member's queue so this can't race with other role changes
+        let key = format!("outbox:{}", operation);
+        self.queue.run(guild_id, user_id, Some(key), || async move {
+          let member = http.get_member(guild_id.0, user_id.0).await?;
+          let old_roles = member.roles.iter().copied().collect::<HashSet<RoleId>>();
+          let mut roles = old_roles.clone();
+          roles.extend(add.iter().copied());
+          for role in remove {
+            roles.remove(role);
+          };
+
+          if roles != old_roles {
+            guild_id.edit_member(http, user_id, |edit| edit.roles(roles)).await?;
+          };
+
+          Ok(())
+        }).await.unwrap_or(Ok(()))
+      },
+      Operation::DeleteReaction { channel_id, message_id, user_id, emoji } => {
+        channel_id.delete_reaction(http, *message_id, Some(*user_id), emoji.clone()).await
+      },
+      Operation::SendMessage { channel_id, content } => {
+        channel_id.say(http, content).await.map(|_| ())
+      }
+    };
+
+    result.observe(&self.metrics)
+  }
+}
+
+/// Retries operations as they come due, until the bot stops
+pub async fn run(outbox: Arc<Outbox>, http: Arc<Http>) {
+  loop {
+    let due = outbox.items.lock().unwrap().iter()
+      .filter(|item| !item.stuck && item.next_attempt <= now())
+      .cloned().collect::<Vec<OutboxItem>>();
+
+    for item in due {
+      let result = outbox.execute(&http, &item.operation).await;
+
+      let mut items = outbox.items.lock().unwrap();
+      let index = match items.iter().position(|other| other.id == item.id) {
+        Some(index) => index,
+        // Cleared while it was being retried
+        None => continue
+      };
+
+      match result {
+        Ok(()) => {
+          log!(Info, "Retried {} successfully", item.operation);
+          items.remove(index);
+        },
+        Err(err) => {
+          let entry = &mut items[index];
+          entry.attempts += 1;
+          entry.next_attempt = now() + backoff(entry.attempts);
+          entry.last_error = Some(format!("{:?}", err));
+          entry.stuck = !is_retryable(&err) || entry.attempts >= MAX_ATTEMPTS;
+          if entry.stuck {
+            log!(Error, "Giving up on {} after {} attempts: {:?}", entry.operation, entry.attempts, err);
+          };
+        }
+      };
+
+      outbox.save(&items);
+    };
+
+    tokio::select! {
+      _ = tokio::time::sleep(POLL_INTERVAL) => (),
+      _ = outbox.wake.notified() => ()
+    };
+  };
+}
+
+/// Whether an error might go away if the operation is tried again
+pub fn is_retryable(err: &SerenityError) -> bool {
+  match err {
+    SerenityError::Http(err) => match &**err {
+      HttpError::UnsuccessfulRequest(response) => is_retryable_status(response.status_code.as_u16()),
+      HttpError::Request(_) => true,
+      _ => false
+    },
+    SerenityError::Io(_) | SerenityError::Gateway(_) => true,
+    // Missing permissions, unknown members and the like
+    _ => false
+  }
+}
+
+/// Rate limits and server errors are worth retrying, anything else Discord refused won't change
+fn is_retryable_status(status: u16) -> bool {
+  matches!(status, 429 | 500..=599)
+}
+
+fn backoff(attempts: u32) -> u64 {
+  BASE_BACKOFF.saturating_mul(1 << attempts.saturating_sub(1).min(20)).min(MAX_BACKOFF)
+}
+
+fn now() -> u64 {
+  SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+  use std::io;
+
+  use super::*;
+
+  #[test]
+  fn backoff_doubles_up_to_the_cap() {
+    assert_eq!(backoff(0), BASE_BACKOFF);
+    assert_eq!(backoff(1), BASE_BACKOFF);
+    assert_eq!(backoff(2), BASE_BACKOFF * 2);
+    assert_eq!(backoff(5), BASE_BACKOFF * 16);
+    assert_eq!(backoff(MAX_ATTEMPTS), MAX_BACKOFF);
+    assert_eq!(backoff(40), MAX_BACKOFF);
+    assert_eq!(backoff(u32::MAX), MAX_BACKOFF);
+  }
+
+  #[test]
+  fn rate_limits_and_server_errors_are_retried() {
+    assert!(is_retryable_status(429));
+    assert!(is_retryable_status(500));
+    assert!(is_retryable_status(502));
+    assert!(is_retryable_status(503));
+    assert!(!is_retryable_status(400));
+    assert!(!is_retryable_status(403));
+    assert!(!is_retryable_status(404));
+  }
+
+  #[test]
+  fn connection_errors_are_retried() {
+    assert!(is_retryable(&SerenityError::Io(io::Error::new(io::ErrorKind::ConnectionReset, "reset"))));
+    assert!(!is_retryable(&SerenityError::Other("missing permissions")));
+  }
+}
@@ -0,0 +1,74 @@
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::sync::Arc;
+use std::sync::Mutex as StdMutex;
+
+use serenity::{
+  prelude::*,
+  model::id::{GuildId, UserId}
+};
+
+pub struct MemberQueueContainer;
+
+impl TypeMapKey for MemberQueueContainer {
+  type Value = Arc<MemberQueue>;
+}
+
+/// Runs actions that change a member's roles one at a time per member, in the order they arrive.
+///
+/// Without this, two actions for the same member could both read the member's roles before either
+/// had written them, and the second write would undo the first.
+#[derive(Debug, Default)]
+pub struct MemberQueue {
+  members: StdMutex<HashMap<(GuildId, UserId), Arc<MemberSlot>>>
+}
+
+#[derive(Debug, Default)]
+struct MemberSlot {
+  /// Held by the action that is currently running
+  turn: Mutex<()>,
+  /// Keys of the actions that are waiting for their turn
+  waiting: StdMutex<HashSet<String>>
+}
+
+impl MemberQueue {
+  pub fn new() -> MemberQueue {
+    MemberQueue::default()
+  }
+
+  /// Waits for every earlier action for this member to finish, then runs this one.
+  ///
+  /// If an action with the same key is already waiting, this one is dropped in its favour and
+  /// `None` is returned. Only give a key to actions that leave the member in the same state however
+  /// many times they run (like setting a rank); relative ones (like promoting) must pass `None`.
+  /// Actions should read the member's state when they run, not beforehand.
+  pub async fn run<F, Fut, T>(&self, guild_id: GuildId, user_id: UserId, key: Option<String>, f: F) -> Option<T>
+  where F: FnOnce() -> Fut, Fut: Future<Output = T> {
+    let slot = Arc::clone(self.members.lock().unwrap().entry((guild_id, user_id)).or_default());
+    if let Some(key) = &key {
+      if !slot.waiting.lock().unwrap().insert(key.clone()) {
+        self.release(guild_id, user_id, slot);
+        return None;
+      };
+    };
+
+    let turn = slot.turn.lock().await;
+    if let Some(key) = &key {
+      slot.waiting.lock().unwrap().remove(key);
+    };
+    let value = f().await;
+    std::mem::drop(turn);
+
+    self.release(guild_id, user_id, slot);
+    Some(value)
+  }
+
+  /// Forgets a member once nothing is running or waiting for them
+  fn release(&self, guild_id: GuildId, user_id: UserId, slot: Arc<MemberSlot>) {
+    let mut members = self.members.lock().unwrap();
+    // One reference is held by the map and the other is ours
+    if Arc::strong_count(&slot) == 2 {
+      members.remove(&(guild_id, user_id));
+    };
+  }
+}
@@ -0,0 +1,229 @@
+use std::sync::Arc;
+use std::sync::Mutex as StdMutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serenity::{
+  prelude::*,
+  model::id::{ChannelId, MessageId, UserId}
+};
+
+use crate::data::recommend::{Recommendation, Status};
+use crate::writer::{Mutation, PersistWriter};
+
+/// Reaction that approves a recommendation on its ballot
+pub const APPROVE: &str = "\u{2705}";
+/// Reaction that denies a recommendation on its ballot
+pub const DENY: &str = "\u{274e}";
+
+pub struct RecommendationsContainer;
+
+impl TypeMapKey for RecommendationsContainer {
+  type Value = Arc<Recommendations>;
+}
+
+/// Rank recommendations and their votes, kept in persist
+pub struct Recommendations {
+  items: StdMutex<Vec<Recommendation>>,
+  writer: Arc<PersistWriter>
+}
+
+impl Recommendations {
+  pub fn new(items: Vec<Recommendation>, writer: Arc<PersistWriter>) -> Recommendations {
+    Recommendations { items: StdMutex::new(items), writer }
+  }
+
+  /// Replaces the recommendations with ones read back from the persist store, after it was
+  /// changed from outside the bot
+  pub fn reload(&self, items: Vec<Recommendation>) {
+    *self.items.lock().unwrap() = items;
+  }
+
+  /// A copy of every recommendation, oldest first
+  pub fn items(&self) -> Vec<Recommendation> {
+    self.items.lock().unwrap().clone()
+  }
+
+  /// Files a new, open recommendation, returning its id. Votes are only counted once its ballot is set.
+  pub fn file(&self, user_id: UserId, rank: String, reason: String, recommender: UserId) -> u64 {
+    let mut items = self.items.lock().unwrap();
+    let id = items.iter().map(|item| item.id + 1).max().unwrap_or(1);
+    items.push(Recommendation {
+      id,
+      user_id,
+      rank,
+      reason,
+      recommender,
+      filed: now(),
+      ballot: None,
+      approvals: Default::default(),
+      denials: Default::default(),
+      status: Status::Open
+    });
+
+    self.save(&items);
+    id
+  }
+
+  pub fn set_ballot(&self, id: u64, ballot: (ChannelId, MessageId)) {
+    let mut items = self.items.lock().unwrap();
+    if let Some(item) = items.iter_mut().find(|item| item.id == id) {
+      item.ballot = Some(ballot);
+      self.save(&items);
+    };
+  }
+
+  /// Drops a recommendation whose ballot couldn't be posted
+  pub fn withdraw(&self, id: u64) {
+    let mut items = self.items.lock().unwrap();
+    items.retain(|item| item.id != id);
+    self.save(&items);
+  }
+
+  /// The open recommendation whose ballot is this message, if any
+  pub fn find_open(&self, ballot: (ChannelId, MessageId)) -> Option<Recommendation> {
+    self.items.lock().unwrap().iter()
+      .find(|item| item.ballot == Some(ballot) && item.status == Status::Open)
+      .cloned()
+  }
+
+  /// Records a vote, replacing any earlier vote from the same user. Once `needed` votes either
+  /// way have been cast, the recommendation is decided and returned.
+  pub fn vote(&self, id: u64, user_id: UserId, approve: bool, needed: usize) -> Option<Recommendation> {
+    let mut items = self.items.lock().unwrap();
+    let item = items.iter_mut().find(|item| item.id == id && item.status == Status::Open)?;
+    match approve {
+      true => {
+        item.denials.remove(&user_id);
+        item.approvals.insert(user_id);
+      },
+      false => {
+        item.approvals.remove(&user_id);
+        item.denials.insert(user_id);
+      }
+    };
+
+    if item.approvals.len() >= needed {
+      item.status = Status::Approved;
+    } else if item.denials.len() >= needed {
+      item.status = Status::Denied;
+    };
+
+    let decided = match item.status {
+      Status::Open => None,
+      _ => Some(item.clone())
+    };
+
+    self.save(&items);
+    decided
+  }
+
+  /// Takes back a vote on an open recommendation
+  pub fn retract(&self, ballot: (ChannelId, MessageId), user_id: UserId, approve: bool) {
+    let mut items = self.items.lock().unwrap();
+    if let Some(item) = items.iter_mut().find(|item| item.ballot == Some(ballot) && item.status == Status::Open) {
+      let removed = match approve {
+        true => item.approvals.remove(&user_id),
+        false => item.denials.remove(&user_id)
+      };
+
+      if removed {
+        self.save(&items);
+      };
+    };
+  }
+
+  pub fn set_status(&self, id: u64, status: Status) {
+    let mut items = self.items.lock().unwrap();
+    if let Some(item) = items.iter_mut().find(|item| item.id == id) {
+      item.status = status;
+      self.save(&items);
+    };
+  }
+
+  fn save(&self, items: &[Recommendation]) {
+    self.writer.send(Mutation::SetRecommendations(items.to_vec()));
+  }
+}
+
+fn now() -> u64 {
+  SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+  use std::future::Future;
+
+  use super::*;
+  use crate::data::format::Format;
+  use crate::data::persist::{Persist, PersistFile};
+  use crate::data::store::Store;
+  use crate::metrics::Metrics;
+
+  const BALLOT: (ChannelId, MessageId) = (ChannelId(10), MessageId(20));
+
+  /// Recommendations with one open ballot, along with the writer task, which has to be kept
+  /// around (but never needs to run) for changes to be accepted
+  fn recommendations() -> (Recommendations, u64, impl Future<Output = ()>) {
+    let store: Box<dyn Store> = Box::new(PersistFile::new("persist.json", Format::Json, Persist::default()));
+    let (writer, task) = PersistWriter::new(Arc::new(RwLock::new(store)), Arc::new(Metrics::new()));
+    let recommendations = Recommendations::new(Vec::new(), Arc::new(writer));
+    let id = recommendations.file(UserId(1), "Corporal".to_owned(), "Good work".to_owned(), UserId(2));
+    recommendations.set_ballot(id, BALLOT);
+    (recommendations, id, task)
+  }
+
+  fn find(recommendations: &Recommendations, id: u64) -> Recommendation {
+    recommendations.items().into_iter().find(|item| item.id == id).unwrap()
+  }
+
+  #[test]
+  fn votes_flip_between_approve_and_deny() {
+    let (recommendations, id, _task) = recommendations();
+    assert_eq!(recommendations.vote(id, UserId(3), true, 2), None);
+    assert_eq!(recommendations.vote(id, UserId(3), false, 2), None);
+
+    let item = find(&recommendations, id);
+    assert!(item.approvals.is_empty());
+    assert_eq!(item.denials, std::iter::once(UserId(3)).collect());
+
+    assert_eq!(recommendations.vote(id, UserId(3), true, 2), None);
+    let item = find(&recommendations, id);
+    assert_eq!(item.approvals, std::iter::once(UserId(3)).collect());
+    assert!(item.denials.is_empty());
+  }
+
+  #[test]
+  fn reaching_needed_decides_once() {
+    let (recommendations, id, _task) = recommendations();
+    assert_eq!(recommendations.vote(id, UserId(3), true, 2), None);
+    // Voting twice the same way doesn't count twice
+    assert_eq!(recommendations.vote(id, UserId(3), true, 2), None);
+
+    let decided = recommendations.vote(id, UserId(4), true, 2).unwrap();
+    assert_eq!(decided.status, Status::Approved);
+    assert_eq!(recommendations.find_open(BALLOT), None);
+    // Later votes on a decided recommendation are ignored
+    assert_eq!(recommendations.vote(id, UserId(5), false, 2), None);
+    assert_eq!(find(&recommendations, id).status, Status::Approved);
+
+    let (recommendations, id, _task) = self::recommendations();
+    recommendations.vote(id, UserId(3), false, 2);
+    assert_eq!(recommendations.vote(id, UserId(4), false, 2).unwrap().status, Status::Denied);
+  }
+
+  #[test]
+  fn retracting_only_removes_the_matching_vote() {
+    let (recommendations, id, _task) = recommendations();
+    recommendations.vote(id, UserId(3), true, 2);
+    recommendations.retract(BALLOT, UserId(3), false);
+    assert_eq!(find(&recommendations, id).approvals, std::iter::once(UserId(3)).collect());
+
+    recommendations.retract(BALLOT, UserId(3), true);
+    assert!(find(&recommendations, id).approvals.is_empty());
+
+    // A retracted vote no longer counts towards the decision
+    recommendations.vote(id, UserId(4), true, 2);
+    assert_eq!(find(&recommendations, id).status, Status::Open);
+    assert_eq!(recommendations.vote(id, UserId(3), true, 2).unwrap().status, Status::Approved);
+  }
+}
@@ -0,0 +1,65 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use serenity::prelude::*;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::error::Error;
+use crate::handler::{ShardManagerContainer, data_get_from};
+use crate::health::HealthContainer;
+use crate::metrics::MetricsContainer;
+use crate::util::ResultExt;
+
+pub async fn serve(address: SocketAddr, data: Arc<RwLock<TypeMap>>) -> Result<(), Error> {
+  let listener = TcpListener::bind(address).await?;
+  log!(Info, "Serving metrics and health checks on http://{}", address);
+  loop {
+    let (stream, _) = listener.accept().await?;
+    let data = Arc::clone(&data);
+    tokio::spawn(async move {
+      respond(stream, data).await.report_with("Failed to respond to HTTP request");
+    });
+  };
+}
+
+async fn respond(mut stream: TcpStream, data: Arc<RwLock<TypeMap>>) -> Result<(), Error> {
+  let mut buf = [0; 1024];
+  let len = stream.read(&mut buf).await?;
+  let request = String::from_utf8_lossy(&buf[..len]);
+  let mut request_line = request.lines().next().unwrap_or("").split_whitespace();
+
+  let (status, body) = match (request_line.next(), request_line.next()) {
+    (Some("GET"), Some("/metrics")) => {
+      let metrics = data_get_from::<MetricsContainer>(&data).await;
+      let shard_manager = data_get_from::<ShardManagerContainer>(&data).await;
+      ("200 OK", metrics.render(&shard_manager).await)
+    },
+    (Some("GET"), Some("/health/live")) => {
+      let health = data_get_from::<HealthContainer>(&data).await;
+      (status_for(health.is_live()), health.describe())
+    },
+    (Some("GET"), Some("/health/ready")) => {
+      let health = data_get_from::<HealthContainer>(&data).await;
+      (status_for(health.is_ready()), health.describe())
+    },
+    (Some("GET"), Some(_)) => ("404 Not Found", "Not Found\n".to_owned()),
+    _ => ("405 Method Not Allowed", "Method Not Allowed\n".to_owned())
+  };
+
+  let response = format!(
+    "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+    status, body.len(), body
+  );
+
+  stream.write_all(response.as_bytes()).await?;
+  stream.shutdown().await?;
+  Ok(())
+}
+
+fn status_for(healthy: bool) -> &'static str {
+  match healthy {
+    true => "200 OK",
+    false => "503 Service Unavailable"
+  }
+}
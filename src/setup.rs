@@ -0,0 +1,274 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, OpenOptions, Permissions};
+use std::io::{self, Write, BufRead};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::Path;
+use std::process::Command;
+use std::str::FromStr;
+
+use serenity::{
+  http::{Http, GuildPagination},
+  model::{
+    channel::{ChannelType, GuildChannel, ReactionType},
+    id::{GuildId, MessageId}
+  }
+};
+
+use crate::cli::Options;
+use crate::data::config::{self, Config, Position, Rank, RoleMenuPosition};
+use crate::data::format::Format;
+use crate::data::migrate::CONFIG_VERSION;
+use crate::data::secrets::SECRETS_PATH;
+use crate::error::Error;
+
+/// Walks the user through creating a config file on the terminal
+pub async fn run(options: &Options) -> Result<(), Error> {
+  let path = options.config.clone()
+    .unwrap_or_else(|| Path::new(config::CONFIG_PATH).with_extension(options.format.unwrap_or(Format::Json).extension()));
+  let format = options.format.or_else(|| Format::from_path(&path)).unwrap_or(Format::Json);
+  if path.exists() && !confirm(&format!("{} already exists, overwrite it?", path.display()))? {
+    return Ok(());
+  };
+
+  let token = prompt_hidden("Bot token")?;
+  let http = Http::new_with_token(&token);
+  let me = http.get_current_user().await?;
+  println!("Signed in as {} ({})", me.name, me.id);
+  let owner = http.get_current_application_info().await?.owner;
+
+  // Guild
+  let guilds = http.get_guilds(&GuildPagination::After(GuildId(0)), 100).await?;
+  if guilds.is_empty() {
+    return Err(Error::Custom("the bot is not in any guilds, invite it to your server first"));
+  };
+
+  let guild_names = guilds.iter().map(|guild| guild.name.clone()).collect::<Vec<String>>();
+  let guild = &guilds[choose_one("Guild", &guild_names)?];
+
+  let mut roles = http.get_guild_roles(guild.id.0).await?;
+  roles.retain(|role| !role.managed && role.id.0 != guild.id.0);
+  roles.sort_by_key(|role| -role.position);
+  let role_names = roles.iter().map(|role| role.name.clone()).collect::<Vec<String>>();
+
+  let mut channels = http.get_channels(guild.id.0).await?;
+  channels.retain(|channel| channel.kind == ChannelType::Text);
+  channels.sort_by_key(|channel| channel.position);
+  if channels.is_empty() {
+    return Err(Error::Custom("the bot cannot see any text channels in that guild"));
+  };
+
+  let channel_names = channels.iter().map(|channel| format!("#{}", channel.name)).collect::<Vec<String>>();
+
+  // Ranks
+  println!("Pick the rank roles, from lowest to highest");
+  let ranks = choose_many("Ranks", &role_names)?.into_iter()
+    .map(|i| Rank { name: roles[i].name.clone(), role: roles[i].id })
+    .collect::<Vec<Rank>>();
+  if ranks.is_empty() {
+    return Err(Error::Custom("at least one rank is required"));
+  };
+
+  let rank_names = ranks.iter().map(|rank| rank.name.clone()).collect::<Vec<String>>();
+  let default_rank = rank_names[choose_one("Default rank", &rank_names)?].clone();
+
+  // Positions
+  let mut positions = Vec::new();
+  for i in choose_many("Position roles", &role_names)? {
+    let role = &roles[i];
+    positions.push(Position {
+      name: role.name.clone(),
+      role: role.id,
+      ranked: confirm(&format!("Should members with {} have a rank?", role.name))?,
+      admin: confirm(&format!("Should members with {} be admins?", role.name))?
+    });
+  };
+
+  let position_names = positions.iter().map(|position| position.name.clone()).collect::<Vec<String>>();
+
+  // Assignables
+  let assignable = choose_many("Assignable roles", &role_names)?.into_iter()
+    .map(|i| (roles[i].name.clone(), roles[i].id))
+    .collect::<BTreeMap<_, _>>();
+
+  // Role menu
+  let menu_channel = &channels[choose_one("Role menu channel", &channel_names)?];
+  let menu_message = pick_menu_message(&http, menu_channel).await?;
+  let mut role_menu_positions = Vec::new();
+  for i in choose_many("Positions on the role menu", &position_names)? {
+    let emoji = loop {
+      let emoji = prompt(&format!("Emoji for {} (paste it, or `<:name:id>` for custom emoji)", position_names[i]))?;
+      match ReactionType::from_str(&emoji) {
+        Ok(emoji) => break emoji,
+        Err(_) => println!("That isn't an emoji, try again")
+      };
+    };
+
+    role_menu_positions.push(RoleMenuPosition { emoji, name: position_names[i].clone() });
+  };
+
+  // Greeting
+  let greetable_positions = choose_many("Positions that trigger a greeting", &position_names)?.into_iter()
+    .map(|i| position_names[i].clone())
+    .collect::<HashSet<String>>();
+  let greeting_channel = channels[choose_one("Greeting channel", &channel_names)?].id;
+  println!("Enter the greeting, one line at a time, use {{mention}} to mention the member. Finish with an empty line");
+  let mut greeting = Vec::new();
+  loop {
+    let line = read_line()?;
+    if line.is_empty() { break };
+    greeting.push(line);
+  };
+
+  let inline_token = !confirm(&format!("Store the token in {} instead of the config file?", SECRETS_PATH))?;
+  let config = Config {
+    version: CONFIG_VERSION,
+    owners: std::iter::once(owner.id).collect(),
+    token: if inline_token { Some(token.clone()) } else { None },
+    token_file: None,
+    guild: guild.id,
+    leave_other_guilds: false,
+    default_rank,
+    ranks,
+    positions,
+    assignable,
+    restricted_assignable: BTreeMap::new(),
+    role_menu: (menu_channel.id, menu_message),
+    role_menu_positions,
+    greetable_positions,
+    greeting_channel,
+    greeting,
+    http_address: None,
+    systemd_notify: false,
+    shutdown_timeout: config::default_shutdown_timeout(),
+    persist_backups: config::default_persist_backups(),
+    offline_notice: None,
+    review: None,
+    permissions: BTreeMap::new()
+  };
+
+  config.validate()?;
+  if !inline_token {
+    let overwrite = !Path::new(SECRETS_PATH).exists() ||
+      confirm(&format!("{} already exists, overwrite it?", SECRETS_PATH))?;
+    match overwrite {
+      true => {
+        let secrets = serde_json::json!({ "token": token });
+        write_private(SECRETS_PATH, &serde_json::to_string_pretty(&secrets)?)?;
+        println!("Wrote {}", SECRETS_PATH);
+      },
+      false => println!("Kept the existing {}, the token in it will be used", SECRETS_PATH)
+    };
+  };
+
+  write_private(&path, &format.to_string(&config)?)?;
+  println!("Wrote {}, run the bot to start it", path.display());
+  Ok(())
+}
+
+async fn pick_menu_message(http: &Http, channel: &GuildChannel) -> Result<MessageId, Error> {
+  if confirm("Post a new role menu message?")? {
+    let text = prompt("Role menu text")?;
+    Ok(channel.id.say(http, text).await?.id)
+  } else {
+    loop {
+      match prompt("Role menu message ID")?.parse::<u64>() {
+        Ok(id) => break Ok(MessageId(id)),
+        Err(_) => println!("That isn't a message ID, try again")
+      };
+    }
+  }
+}
+
+/// Writes a file that only its owner may read, since it may contain the token
+fn write_private(path: impl AsRef<Path>, contents: &str) -> Result<(), Error> {
+  let mut file = OpenOptions::new()
+    .write(true).create(true).truncate(true)
+    .mode(0o600).open(&path)?;
+  // The mode only applies to new files, an existing file keeps its own until it is changed
+  fs::set_permissions(&path, Permissions::from_mode(0o600))?;
+  file.write_all(contents.as_bytes())?;
+  Ok(())
+}
+
+fn read_line() -> Result<String, Error> {
+  let mut line = String::new();
+  if io::stdin().lock().read_line(&mut line)? == 0 {
+    return Err(Error::Custom("setup cancelled"));
+  };
+
+  Ok(line.trim().to_owned())
+}
+
+fn prompt(question: &str) -> Result<String, Error> {
+  print!("{}: ", question);
+  io::stdout().flush()?;
+  read_line()
+}
+
+/// Like `prompt`, but without echoing the answer to the terminal
+fn prompt_hidden(question: &str) -> Result<String, Error> {
+  print!("{}: ", question);
+  io::stdout().flush()?;
+  let hidden = set_echo(false);
+  let answer = read_line();
+  if hidden {
+    set_echo(true);
+    println!();
+  };
+
+  answer
+}
+
+/// Turns terminal echo on or off, returning whether that worked (it doesn't if stdin isn't a terminal)
+fn set_echo(echo: bool) -> bool {
+  Command::new("stty")
+    .arg(if echo { "echo" } else { "-echo" })
+    .status()
+    .is_ok_and(|status| status.success())
+}
+
+fn confirm(question: &str) -> Result<bool, Error> {
+  loop {
+    match prompt(&format!("{} [y/n]", question))?.to_lowercase().as_str() {
+      "y" | "yes" => return Ok(true),
+      "n" | "no" => return Ok(false),
+      _ => println!("Please answer y or n")
+    };
+  }
+}
+
+fn list(options: &[String]) {
+  for (i, option) in options.iter().enumerate() {
+    println!("  {:>3}. {}", i + 1, option);
+  };
+}
+
+fn choose_one(question: &str, options: &[String]) -> Result<usize, Error> {
+  list(options);
+  loop {
+    match prompt(&format!("{} (number)", question))?.parse::<usize>() {
+      Ok(i) if i >= 1 && i <= options.len() => return Ok(i - 1),
+      _ => println!("Pick a number from the list")
+    };
+  }
+}
+
+/// Asks for a comma separated list of numbers, keeping the order they were given in
+fn choose_many(question: &str, options: &[String]) -> Result<Vec<usize>, Error> {
+  list(options);
+  'retry: loop {
+    let answer = prompt(&format!("{} (comma separated numbers, or blank for none)", question))?;
+    let mut chosen = Vec::new();
+    for part in answer.split(',').map(str::trim).filter(|part| !part.is_empty()) {
+      match part.parse::<usize>() {
+        Ok(i) if i >= 1 && i <= options.len() => chosen.push(i - 1),
+        _ => {
+          println!("{} isn't a number from the list", part);
+          continue 'retry;
+        }
+      };
+    };
+
+    return Ok(chosen);
+  }
+}
@@ -0,0 +1,128 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use serenity::{
+  prelude::*,
+  client::bridge::gateway::ShardManager,
+  http::Http
+};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::Notify;
+
+use crate::data::config::ConfigContainer;
+use crate::error::Error;
+use crate::handler::data_get_from;
+use crate::health::HealthContainer;
+use crate::util::ResultExt;
+use crate::writer::PersistWriterContainer;
+
+pub struct ShutdownContainer;
+
+impl TypeMapKey for ShutdownContainer {
+  type Value = Arc<Shutdown>;
+}
+
+/// Coordinates stopping the bot without cutting off work that is already underway
+#[derive(Debug, Default)]
+pub struct Shutdown {
+  stopping: AtomicBool,
+  in_flight: AtomicUsize,
+  requested: Notify
+}
+
+impl Shutdown {
+  pub fn new() -> Shutdown {
+    Shutdown::default()
+  }
+
+  pub fn is_stopping(&self) -> bool {
+    self.stopping.load(Ordering::SeqCst)
+  }
+
+  /// Registers a unit of in-flight work, returning false if the bot is shutting down.
+  /// Every successful call must be paired with a call to `exit`.
+  pub fn enter(&self) -> bool {
+    self.in_flight.fetch_add(1, Ordering::SeqCst);
+    if self.is_stopping() {
+      self.exit();
+      false
+    } else {
+      true
+    }
+  }
+
+  pub fn exit(&self) {
+    self.in_flight.fetch_sub(1, Ordering::SeqCst);
+  }
+
+  /// Like `enter`, but returns a guard that calls `exit` when dropped
+  pub fn guard(self: &Arc<Self>) -> Option<InFlight> {
+    match self.enter() {
+      true => Some(InFlight { shutdown: Arc::clone(self) }),
+      false => None
+    }
+  }
+
+  /// Asks the shutdown task to begin shutting the bot down
+  pub fn request(&self) {
+    self.requested.notify_one();
+  }
+
+  async fn wait_idle(&self, timeout: Duration) -> bool {
+    let start = Instant::now();
+    while self.in_flight.load(Ordering::SeqCst) > 0 {
+      if start.elapsed() >= timeout { return false };
+      tokio::time::sleep(Duration::from_millis(100)).await;
+    };
+
+    true
+  }
+}
+
+#[derive(Debug)]
+pub struct InFlight {
+  shutdown: Arc<Shutdown>
+}
+
+impl Drop for InFlight {
+  fn drop(&mut self) {
+    self.shutdown.exit();
+  }
+}
+
+/// Waits for SIGINT, SIGTERM or a shutdown request, then stops the bot
+pub async fn run(
+  shutdown: Arc<Shutdown>,
+  data: Arc<RwLock<TypeMap>>,
+  http: Arc<Http>,
+  shard_manager: Arc<Mutex<ShardManager>>
+) -> Result<(), Error> {
+  let mut sigterm = signal(SignalKind::terminate())?;
+  tokio::select! {
+    _ = tokio::signal::ctrl_c() => log!(Info, "Received SIGINT, shutting down"),
+    _ = sigterm.recv() => log!(Info, "Received SIGTERM, shutting down"),
+    _ = shutdown.requested.notified() => log!(Info, "Shutdown requested")
+  };
+
+  // Stop accepting new commands and reactions
+  shutdown.stopping.store(true, Ordering::SeqCst);
+  data_get_from::<HealthContainer>(&data).await.notify_stopping();
+
+  let config = data_get_from::<ConfigContainer>(&data).await;
+  let timeout = Duration::from_secs(config.load().shutdown_timeout);
+  if !shutdown.wait_idle(timeout).await {
+    log!(Error, "Error: Timed out waiting for in-flight work to finish");
+  };
+
+  data_get_from::<PersistWriterContainer>(&data).await.flush().await
+    .report_with("Failed to commit persist");
+
+  if let Some((channel_id, notice)) = &config.load().offline_notice {
+    channel_id.say(&http, notice).await
+      .report_with("Failed to send offline notice");
+  };
+
+  shard_manager.lock().await.shutdown_all().await;
+  Ok(())
+}
@@ -0,0 +1,50 @@
+use std::env;
+use std::os::unix::net::UnixDatagram;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::health::Health;
+use crate::util::ResultExt;
+
+/// Sends a state update to the service manager, if we were started by one
+pub fn notify(state: &str) {
+  let socket_path = match env::var_os("NOTIFY_SOCKET") {
+    Some(socket_path) => socket_path,
+    None => return
+  };
+
+  if socket_path.to_string_lossy().starts_with('@') {
+    log!(Error, "Error: Abstract notify sockets are not supported");
+    return;
+  };
+
+  let result = UnixDatagram::unbound()
+    .and_then(|socket| socket.send_to(state.as_bytes(), &socket_path));
+  result.report_with("Failed to notify systemd");
+}
+
+/// Returns the watchdog interval requested by the service manager, if any
+pub fn watchdog_interval() -> Option<Duration> {
+  let usec = env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok()?;
+  if let Some(pid) = env::var("WATCHDOG_PID").ok().and_then(|pid| pid.parse::<u32>().ok()) {
+    if pid != std::process::id() { return None };
+  };
+
+  Some(Duration::from_micros(usec))
+}
+
+/// Pings the watchdog at half the requested interval for as long as the bot is healthy,
+/// so that systemd restarts the bot if it hangs or stays disconnected
+pub async fn run_watchdog(health: Arc<Health>) {
+  let interval = match watchdog_interval() {
+    Some(interval) => interval / 2,
+    None => return
+  };
+
+  loop {
+    tokio::time::sleep(interval).await;
+    if health.is_live() {
+      notify("WATCHDOG=1");
+    };
+  };
+}
@@ -1,11 +1,41 @@
+use std::sync::atomic::{AtomicU8, Ordering};
+
+static LOG_LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Info as u8);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+  Error,
+  Warn,
+  Info,
+  Debug
+}
+
+impl LogLevel {
+  pub fn from_name(name: &str) -> Option<LogLevel> {
+    match name.to_lowercase().as_str() {
+      "error" => Some(LogLevel::Error),
+      "warn" => Some(LogLevel::Warn),
+      "info" => Some(LogLevel::Info),
+      "debug" => Some(LogLevel::Debug),
+      _ => None
+    }
+  }
+}
+
+pub fn set_log_level(level: LogLevel) {
+  LOG_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn log_enabled(level: LogLevel) -> bool {
+  level as u8 <= LOG_LEVEL.load(Ordering::Relaxed)
+}
+
 pub trait ResultExt<T, E> {
   fn report_with(self, msg: &str)
   where E: std::fmt::Debug;
 
   fn report(self)
   where E: std::fmt::Debug;
-
-  fn ignore(self);
 }
 
 impl<T, E> ResultExt<T, E> for Result<T, E> {
@@ -13,7 +43,7 @@ impl<T, E> ResultExt<T, E> for Result<T, E> {
   fn report_with(self, msg: &str)
   where E: std::fmt::Debug {
     if let Err(e) = self {
-      println!("{}: {:?}", msg, e);
+      log!(Error, "{}: {:?}", msg, e);
     };
   }
 
@@ -21,10 +51,15 @@ impl<T, E> ResultExt<T, E> for Result<T, E> {
   fn report(self)
   where E: std::fmt::Debug {
     if let Err(e) = self {
-      println!("{:?}", e);
+      log!(Error, "{:?}", e);
     };
   }
+}
 
-  #[inline]
-  fn ignore(self) {}
+/// Shortens a string to at most `max` bytes, ending it with an ellipsis if it was cut off
+pub fn truncate(s: &str, max: usize) -> String {
+  if s.len() <= max { return s.to_owned() };
+  let mut end = max;
+  while !s.is_char_boundary(end) { end -= 1 };
+  format!("{}\u{2026}", &s[..end])
 }
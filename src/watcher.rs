@@ -0,0 +1,106 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use serenity::{
+  prelude::*,
+  cache::Cache
+};
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::data::{self, config::ConfigContainer, persist::PersistContainer};
+use crate::error::Error;
+use crate::handler::data_get_from;
+use crate::util::ResultExt;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// How long a file must go unchanged before it is reloaded
+const DEBOUNCE: Duration = Duration::from_secs(2);
+
+/// Reloads the config and persist files when they change on disk or when SIGHUP is received
+pub async fn run(data: Arc<RwLock<TypeMap>>, cache: Arc<Cache>) -> Result<(), Error> {
+  let mut sighup = signal(SignalKind::hangup())?;
+  let config_paths = data_get_from::<ConfigContainer>(&data).await.load().paths();
+  let mut config_watches = config_paths.into_iter().map(Watch::new).collect::<Vec<Watch>>();
+  let persist_path = data_get_from::<PersistContainer>(&data).await.read().await.path().to_owned();
+  let mut persist_watch = Watch::new(persist_path);
+  let mut interval = tokio::time::interval(POLL_INTERVAL);
+
+  loop {
+    tokio::select! {
+      _ = sighup.recv() => {
+        log!(Info, "Received SIGHUP, reloading");
+        reload_config(&data, &cache).await;
+        reload_persist(&data).await;
+      },
+      _ = interval.tick() => {
+        // Poll every layer so that none of them are left with a stale pending change
+        let mut config_changed = false;
+        for watch in config_watches.iter_mut() {
+          config_changed |= watch.poll();
+        };
+
+        if config_changed {
+          reload_config(&data, &cache).await;
+        };
+
+        if persist_watch.poll() {
+          reload_persist(&data).await;
+        };
+      }
+    };
+  };
+}
+
+async fn reload_config(data: &RwLock<TypeMap>, cache: &Cache) {
+  match data::reload_config(data).await {
+    Ok(diff) => {
+      let guild_id = data_get_from::<ConfigContainer>(data).await.load().guild;
+      let warnings = diff.warnings(&data::member_roles(cache, guild_id).await);
+      data::log_config_diff(&diff, &warnings);
+    },
+    Err(err) => log!(Error, "Failed to reload config, keeping previous config: {:?}", err)
+  };
+}
+
+async fn reload_persist(data: &RwLock<TypeMap>) {
+  data::reload_persist(data).await
+    .map(|changed| if changed { log!(Info, "Persist reloaded") })
+    .report_with("Failed to reload persist");
+}
+
+struct Watch {
+  path: PathBuf,
+  modified: Option<SystemTime>,
+  pending_since: Option<Instant>
+}
+
+impl Watch {
+  fn new(path: PathBuf) -> Watch {
+    Watch {
+      modified: modified(&path),
+      path,
+      pending_since: None
+    }
+  }
+
+  /// Returns true once the file has changed and then settled for the debounce period
+  fn poll(&mut self) -> bool {
+    let modified = modified(&self.path);
+    if modified != self.modified {
+      self.modified = modified;
+      self.pending_since = Some(Instant::now());
+      false
+    } else if self.pending_since.is_some_and(|since| since.elapsed() >= DEBOUNCE) {
+      self.pending_since = None;
+      true
+    } else {
+      false
+    }
+  }
+}
+
+fn modified(path: impl AsRef<Path>) -> Option<SystemTime> {
+  fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
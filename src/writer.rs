@@ -0,0 +1,213 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::sync::Mutex as StdMutex;
+use std::time::Duration;
+
+use serenity::{
+  prelude::*,
+  model::id::UserId
+};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::data::outbox::OutboxItem;
+use crate::data::recommend::Recommendation;
+use crate::data::store::Store;
+use crate::error::Error;
+use crate::metrics::Metrics;
+
+/// How long to keep collecting mutations after the first one before writing them
+const DEBOUNCE: Duration = Duration::from_secs(1);
+
+pub struct PersistWriterContainer;
+
+impl TypeMapKey for PersistWriterContainer {
+  type Value = Arc<PersistWriter>;
+}
+
+/// A change to the persist state, to be applied by the writer task
+#[derive(Debug)]
+pub enum Mutation {
+  RegisterGreeted(UserId),
+  SetGreeted(HashSet<UserId>),
+  SetOutbox(Vec<OutboxItem>),
+  SetRecommendations(Vec<Recommendation>)
+}
+
+enum Message {
+  Mutation(Mutation),
+  Flush(oneshot::Sender<Result<(), String>>)
+}
+
+/// A handle to the writer task, which owns all writes to the persist store.
+///
+/// Mutations sent here are coalesced and written in batches off the async executor, so
+/// handlers never need to hold the persist lock while doing anything else.
+pub struct PersistWriter {
+  sender: mpsc::UnboundedSender<Message>,
+  persist: Arc<RwLock<Box<dyn Store>>>,
+  /// Users that have been registered as greeted but not written yet
+  unwritten: Arc<StdMutex<HashSet<UserId>>>
+}
+
+impl PersistWriter {
+  /// Creates a writer for a store, returning the future that runs the writer task
+  pub fn new(persist: Arc<RwLock<Box<dyn Store>>>, metrics: Arc<Metrics>) -> (PersistWriter, impl std::future::Future<Output = ()>) {
+    let (sender, receiver) = mpsc::unbounded_channel();
+    let unwritten = Arc::new(StdMutex::new(HashSet::new()));
+    let task = run(receiver, Arc::clone(&persist), metrics, Arc::clone(&unwritten));
+    (PersistWriter { sender, persist, unwritten }, task)
+  }
+
+  pub fn send(&self, mutation: Mutation) {
+    if self.sender.send(Message::Mutation(mutation)).is_err() {
+      log!(Error, "Error: Persist writer has stopped, dropping a change");
+    };
+  }
+
+  /// Registers a user as greeted, returning false if they already were (or are about to be)
+  pub async fn claim_greeting(&self, user_id: UserId) -> Result<bool, Error> {
+    if self.unwritten.lock().unwrap().contains(&user_id) {
+      return Ok(false);
+    };
+
+    let should_greet = self.persist.read().await.should_greet(user_id)?;
+    if should_greet && self.unwritten.lock().unwrap().insert(user_id) {
+      self.send(Mutation::RegisterGreeted(user_id));
+      Ok(true)
+    } else {
+      Ok(false)
+    }
+  }
+
+  /// Writes every mutation sent so far, waiting until they are on disk
+  pub async fn flush(&self) -> Result<(), Error> {
+    let (sender, receiver) = oneshot::channel();
+    self.sender.send(Message::Flush(sender))
+      .map_err(|_| Error::Custom("the persist writer has stopped"))?;
+    receiver.await
+      .map_err(|_| Error::Custom("the persist writer has stopped"))?
+      .map_err(Error::Invalid)
+  }
+}
+
+/// Mutations that have been received but not written yet
+#[derive(Debug, Clone, Default)]
+struct Batch {
+  /// Replaces the greeted users before `greeted` is applied, if set
+  replace_greeted: Option<HashSet<UserId>>,
+  greeted: HashSet<UserId>,
+  /// Only the latest outbox matters
+  outbox: Option<Vec<OutboxItem>>,
+  /// Likewise for recommendations
+  recommendations: Option<Vec<Recommendation>>
+}
+
+impl Batch {
+  fn push(&mut self, mutation: Mutation) {
+    match (mutation, &mut self.replace_greeted) {
+      (Mutation::RegisterGreeted(user_id), Some(replace_greeted)) => { replace_greeted.insert(user_id); },
+      (Mutation::RegisterGreeted(user_id), None) => { self.greeted.insert(user_id); },
+      (Mutation::SetGreeted(user_ids), _) => {
+        self.greeted.clear();
+        self.replace_greeted = Some(user_ids);
+      },
+      (Mutation::SetOutbox(items), _) => self.outbox = Some(items),
+      (Mutation::SetRecommendations(recommendations), _) => self.recommendations = Some(recommendations)
+    };
+  }
+
+  fn is_empty(&self) -> bool {
+    self.replace_greeted.is_none() && self.greeted.is_empty() &&
+    self.outbox.is_none() && self.recommendations.is_none()
+  }
+
+  fn apply(self, store: &mut dyn Store) -> Result<(), Error> {
+    if let Some(user_ids) = self.replace_greeted {
+      store.set_greeted(user_ids)?;
+    };
+
+    for user_id in self.greeted {
+      store.register_greeted(user_id)?;
+    };
+
+    if let Some(items) = self.outbox {
+      store.set_outbox(items)?;
+    };
+
+    if let Some(recommendations) = self.recommendations {
+      store.set_recommendations(recommendations)?;
+    };
+
+    store.commit()
+  }
+}
+
+async fn run(
+  mut receiver: mpsc::UnboundedReceiver<Message>,
+  persist: Arc<RwLock<Box<dyn Store>>>,
+  metrics: Arc<Metrics>,
+  unwritten: Arc<StdMutex<HashSet<UserId>>>
+) {
+  let mut batch = Batch::default();
+  let mut flushes = Vec::new();
+  let mut closed = false;
+
+  loop {
+    // A batch left over from a failed write is retried after the debounce period
+    if batch.is_empty() {
+      match receiver.recv().await {
+        Some(message) => receive(message, &mut batch, &mut flushes),
+        None => return
+      };
+    };
+
+    // Collect more mutations until the debounce period ends or a flush is requested
+    let deadline = tokio::time::sleep(DEBOUNCE);
+    tokio::pin!(deadline);
+    while flushes.is_empty() && !closed {
+      tokio::select! {
+        _ = &mut deadline => break,
+        message = receiver.recv() => match message {
+          Some(message) => receive(message, &mut batch, &mut flushes),
+          None => closed = true
+        }
+      };
+    };
+
+    let written = batch.greeted.iter()
+      .chain(batch.replace_greeted.iter().flatten())
+      .copied().collect::<Vec<UserId>>();
+    let result = write(Arc::clone(&persist), Arc::clone(&metrics), batch.clone()).await;
+    match &result {
+      Ok(()) => {
+        batch = Batch::default();
+        let mut unwritten = unwritten.lock().unwrap();
+        for user_id in written {
+          unwritten.remove(&user_id);
+        };
+      },
+      Err(err) => log!(Error, "Failed to write persist, will retry: {:?}", err)
+    };
+
+    for flush in flushes.drain(..) {
+      flush.send(result.as_ref().map(|_| ()).map_err(Error::describe)).ok();
+    };
+
+    if closed { return };
+  };
+}
+
+fn receive(message: Message, batch: &mut Batch, flushes: &mut Vec<oneshot::Sender<Result<(), String>>>) {
+  match message {
+    Message::Mutation(mutation) => batch.push(mutation),
+    Message::Flush(sender) => flushes.push(sender)
+  };
+}
+
+/// Applies a batch and commits it on a blocking thread, so slow disks don't stall the executor
+async fn write(persist: Arc<RwLock<Box<dyn Store>>>, metrics: Arc<Metrics>, batch: Batch) -> Result<(), Error> {
+  tokio::task::spawn_blocking(move || {
+    let mut persist_lock = persist.blocking_write();
+    metrics.time_persist_commit(|| batch.apply(&mut **persist_lock))
+  }).await.map_err(|_| Error::Custom("the persist writer panicked"))?
+}